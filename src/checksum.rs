@@ -0,0 +1,81 @@
+use std::fs::File;
+use std::io::{ self, Write };
+use std::path::Path;
+use xxhash_rust::xxh3::Xxh3;
+
+/// Response header carrying the checksum of the whole file (even for partial responses)
+pub const CHECKSUM_HEADER: &str = "X-Jbodncp-Xxh3";
+/// Response header telling that the server keeps checksums but doesn't know this one yet: GET /checksum once the file is complete.
+/// For a whole-file response, it's computed as the file is sent
+pub const CHECKSUM_PENDING_HEADER: &str = "X-Jbodncp-Xxh3-Pending";
+
+#[derive(Default, Clone)]
+pub struct Checksum(Xxh3);
+
+impl Checksum {
+    pub fn new() -> Checksum {
+        Checksum(Xxh3::new())
+    }
+    pub fn finish(&self) -> String {
+        format!("{:032x}", self.0.digest128())
+    }
+}
+
+impl Write for Checksum {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Computes the checksum of everything written through it
+pub struct HashingWriter<W> {
+    pub inner: W,
+    pub checksum: Checksum,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> HashingWriter<W> {
+        HashingWriter { inner, checksum: Checksum::new() }
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.checksum.0.update(&buf[..len]);
+        Ok(len)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut checksum = Checksum::new();
+    io::copy(&mut File::open(path)?, &mut checksum)?;
+    Ok(checksum.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_hashing_writer() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+        std::fs::write(&path, b"oneonetwotwo").unwrap();
+
+        let mut writer = HashingWriter::new(Vec::new());
+        writer.write_all(b"oneone").unwrap();
+        writer.write_all(b"twotwo").unwrap();
+        assert_eq!(writer.inner, b"oneonetwotwo");
+        assert_eq!(writer.checksum.finish(), hash_file(&path).unwrap());
+        assert_ne!(Checksum::new().finish(), hash_file(&path).unwrap());
+    }
+}
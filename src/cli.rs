@@ -1,20 +1,88 @@
-use clap::{ Parser, Subcommand, Args };
+use clap::{ Parser, Subcommand, Args, ValueEnum, CommandFactory, FromArgMatches };
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::{ SystemTime, UNIX_EPOCH };
+use crate::filter::FilterRule;
+use crate::ratelimit::BwSchedule;
+use crate::metadata::parse_id_mapping;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct RunArgs {
+    /// Enable debug logging (e.g. a line per transferred file)
+    #[arg(short, long, global=true)]
+    pub verbose: bool,
     #[clap(subcommand)]
     pub cmd: SubCommand,
 }
 
+impl RunArgs {
+    /// Same as parse(), also keeps track of the order of the --include and --exclude options
+    pub fn parse_ordered() -> RunArgs {
+        RunArgs::parse_ordered_from(std::env::args_os())
+    }
+    fn parse_ordered_from<I: IntoIterator<Item = T>, T: Into<OsString> + Clone>(args: I) -> RunArgs {
+        let matches = RunArgs::command().get_matches_from(args);
+        let mut args = RunArgs::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+        if let (SubCommand::Download(config), Some((_, matches))) = (&mut args.cmd, matches.subcommand()) {
+            let mut rules: Vec<(usize, FilterRule)> = vec![];
+            for id in ["include", "exclude"] {
+                if let (Some(indices), Some(values)) = (matches.indices_of(id), matches.get_many::<FilterRule>(id)) {
+                    rules.extend(indices.zip(values.cloned()));
+                }
+            }
+            rules.sort_by_key(|(idx, _rule)| *idx);
+            config.filter_rules = rules.into_iter().map(|(_idx, rule)| rule).collect();
+        }
+        args
+    }
+}
+
 #[derive(Subcommand, Debug)]
+// Only built once, so it isn't worth boxing
+#[allow(clippy::large_enum_variant)]
 pub enum SubCommand {
-    Serve {
-        src_paths: Vec<String>,
-        #[arg(long, default_value_t=3000)]
-        port: u16,
-    },
+    Serve(#[clap(flatten)] ServeConfig),
     Download(#[clap(flatten)] DownloadConfig),
+    /// Compare the destination with the server without transferring anything
+    Verify(#[clap(flatten)] VerifyConfig),
+}
+
+#[derive(Args, Debug)]
+pub struct ServeConfig {
+    pub src_paths: Vec<String>,
+    #[arg(long, default_value_t=3000)]
+    pub port: u16,
+    /// Send an xxh3 checksum of every served file, so that clients can verify their downloads
+    #[arg(long)]
+    pub checksum: bool,
+    /// Total upload rate limit in bytes per second, e.g. "200M", or a schedule like "08:00,200M 18:00,off"
+    #[arg(long)]
+    pub bwlimit: Option<BwSchedule>,
+    /// Maximum number of simultaneous downloads, further requests are answered with 429 Too Many Requests
+    #[arg(long)]
+    pub max_streams: Option<usize>,
+    /// Maximum number of simultaneous downloads per client IP address
+    #[arg(long)]
+    pub max_streams_per_client: Option<usize>,
+    /// Threads reading the directories of each source path, the source paths are always walked concurrently
+    #[arg(long, default_value_t=1)]
+    pub walk_threads: usize,
+    /// Don't cross into other file systems (bind mounts, nested mounts) while walking the source paths
+    #[arg(long)]
+    pub one_file_system: bool,
+    /// Serve the files symlinks point to even when they are outside of the source paths
+    #[arg(long)]
+    pub allow_external_symlinks: bool,
+    /// Keep the file list in memory rather than walking the disks for every /list request
+    #[arg(long)]
+    pub index: bool,
+    /// How often the --index is refreshed, in seconds. Only the directories whose mtime has changed are read again
+    #[arg(long, default_value_t=60)]
+    pub index_refresh: u64,
+    /// How often the --index is refreshed by reading every directory again, in seconds, so that in-place changes to the files show up. 0 disables it
+    #[arg(long, default_value_t=3600)]
+    pub index_full_rescan: u64,
 }
 
 #[derive(Args, Debug)]
@@ -31,4 +99,208 @@ pub struct DownloadConfig {
     pub group_by: Option<String>,
     #[arg(long)]
     pub group_by_preload: Option<String>,
+    /// How many times a failed file download is retried before giving up on it
+    #[arg(long, default_value_t=3)]
+    pub retries: u32,
+    /// Initial delay between retries in seconds, doubled after each attempt
+    #[arg(long, default_value_t=1.0)]
+    pub retry_delay: f64,
+    /// Files larger than this are split into segments downloaded by several workers at once (0 disables)
+    #[arg(long, default_value="4G", value_parser=parse_size)]
+    pub segment_threshold: u64,
+    #[arg(long, default_value="1G", value_parser=parse_size)]
+    pub segment_size: u64,
+    /// Total download rate limit in bytes per second, e.g. "200M", or a schedule like "08:00,200M 18:00,off"
+    #[arg(long)]
+    pub bwlimit: Option<BwSchedule>,
+    /// Seconds between progress reports (0 disables), a progress bar is drawn instead when stderr is a terminal
+    #[arg(long, default_value_t=10)]
+    pub progress_interval: u64,
+    /// Write a JSON summary of the run into this file
+    #[arg(long)]
+    pub report: Option<String>,
+    /// Map the owner of the original files onto another uid (FROM:TO, may be repeated).
+    /// Original owners are only preserved when running as root
+    #[arg(long, value_parser=parse_id_mapping)]
+    pub uid_map: Vec<(u32, u32)>,
+    /// Same as --uid-map, for groups
+    #[arg(long, value_parser=parse_id_mapping)]
+    pub gid_map: Vec<(u32, u32)>,
+    /// Ask the server to compress the transfers (zstd or gzip), already compressed media is sent as is
+    #[arg(long)]
+    pub compress: bool,
+    /// Mirror mode: once everything is transferred, list the files which aren't on the server anymore,
+    /// as well as the copies of a file on other disks than the one in use. Excluded files are kept.
+    /// They are only deleted with --confirm-delete, once the whole list has been logged
+    #[arg(long)]
+    pub delete: bool,
+    /// Carry out the deletions planned by --delete
+    #[arg(long, requires = "delete")]
+    pub confirm_delete: bool,
+    /// Abort --delete if it would remove more than this percentage of the files found on the destination
+    #[arg(long, default_value_t=10.0)]
+    pub max_delete_percent: f64,
+    /// How the existing files are told up to date: same size, same size and modification time,
+    /// or same size and checksum (which reads the files on both sides)
+    #[arg(long, value_enum, default_value_t=CompareMode::Size)]
+    pub compare: CompareMode,
+    /// Only download this subtree of the server, e.g. "00042/"
+    #[arg(long)]
+    pub prefix: Option<PathBuf>,
+    /// Threads reading the directories of each destination path for --group-by, the destination paths are always walked concurrently
+    #[arg(long, default_value_t=1)]
+    pub walk_threads: usize,
+    /// Ask the server to read its disks again before sending the list, rather than relying on its --index
+    #[arg(long)]
+    pub rescan: bool,
+    /// Download the paths matching this pattern (rsync-like, may be repeated).
+    /// --include and --exclude are evaluated in the given order, the first matching one wins
+    #[arg(long, value_parser=FilterRule::include)]
+    pub include: Vec<FilterRule>,
+    /// Skip the paths matching this pattern (rsync-like, may be repeated)
+    #[arg(long, value_parser=FilterRule::exclude)]
+    pub exclude: Vec<FilterRule>,
+    /// Skip the files smaller than this, e.g. "100K"
+    #[arg(long, value_parser=parse_size)]
+    pub min_size: Option<u64>,
+    /// Skip the files larger than this, e.g. "10G"
+    #[arg(long, value_parser=parse_size)]
+    pub max_size: Option<u64>,
+    /// Skip the files modified before this time: "2026-01-31", "2026-01-31 18:00", "@1769817600" or an age like "7d"
+    #[arg(long, value_parser=parse_time)]
+    pub newer_than: Option<i64>,
+    // --include and --exclude in the order they were given, see RunArgs::parse_ordered
+    #[arg(skip)]
+    pub filter_rules: Vec<FilterRule>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum CompareMode {
+    Size,
+    Mtime,
+    Checksum,
+}
+
+#[derive(Args, Debug)]
+pub struct VerifyConfig {
+    pub url: String,
+    pub dst_paths: Vec<String>,
+    #[arg(long)]
+    pub auth: String,
+    /// Compare the checksums of the files as well, which reads all of them on both sides
+    #[arg(long)]
+    pub checksum: bool,
+    /// Number of files hashed at once with --checksum
+    #[arg(long, default_value_t=4)]
+    pub threads: u16,
+    /// Only verify this subtree of the server, e.g. "00042/"
+    #[arg(long)]
+    pub prefix: Option<PathBuf>,
+    /// Write the differences found into this JSON file
+    #[arg(long)]
+    pub report: Option<String>,
+}
+
+/// Parses sizes like "512K", "200M" or "1.5G" (binary multiples)
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("invalid size: {value}"))?;
+    let unit = unit.to_ascii_uppercase();
+    let multiplier: u64 = match unit.trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("invalid size unit: {value}")),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parses a point in time into a unix timestamp: a local date with an optional time, "@" followed by a timestamp, or an age like "12h"
+pub fn parse_time(value: &str) -> Result<i64, String> {
+    let value = value.trim();
+    let invalid = || format!("invalid time: {value}");
+    if let Some(timestamp) = value.strip_prefix('@') {
+        return timestamp.parse().map_err(|_| invalid());
+    }
+
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let seconds = match unit {
+        "s" => Some(1),
+        "m" => Some(60),
+        "h" => Some(3600),
+        "d" => Some(86400),
+        "w" => Some(7 * 86400),
+        _ => None,
+    };
+    if let (Ok(number), Some(seconds)) = (number.parse::<i64>(), seconds) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        return Ok(now - number * seconds);
+    }
+
+    // YYYY-MM-DD[( |T)HH:MM[:SS]]
+    let fields: Vec<i32> = value.split(['-', ' ', 'T', ':']).map(|field| field.parse().map_err(|_| invalid())).collect::<Result<_, _>>()?;
+    if !(fields.len() == 3 || fields.len() == 5 || fields.len() == 6) {
+        return Err(invalid());
+    }
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = fields[0] - 1900;
+    tm.tm_mon = fields[1] - 1;
+    tm.tm_mday = fields[2];
+    tm.tm_hour = fields.get(3).copied().unwrap_or(0);
+    tm.tm_min = fields.get(4).copied().unwrap_or(0);
+    tm.tm_sec = fields.get(5).copied().unwrap_or(0);
+    tm.tm_isdst = -1;
+    match unsafe { libc::mktime(&mut tm) } {
+        -1 => Err(invalid()),
+        timestamp => Ok(timestamp),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filelist::FileEntry;
+    use crate::filter::Filter;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("512K"), Ok(512 << 10));
+        assert_eq!(parse_size("200M"), Ok(200 << 20));
+        assert_eq!(parse_size("200MiB"), Ok(200 << 20));
+        assert_eq!(parse_size("1.5g"), Ok(3 << 29));
+        assert_eq!(parse_size("2TB"), Ok(2 << 40));
+        assert!(parse_size("12X").is_err());
+        assert!(parse_size("M").is_err());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("@1769817600"), Ok(1769817600));
+        assert_eq!(parse_time("2026-02-01").unwrap() - parse_time("2026-01-31").unwrap(), 86400);
+        assert_eq!(parse_time("2026-01-31 18:30").unwrap() - parse_time("2026-01-31").unwrap(), 18 * 3600 + 30 * 60);
+        assert_eq!(parse_time("2026-01-31T18:30:15").unwrap() - parse_time("2026-01-31").unwrap(), 18 * 3600 + 30 * 60 + 15);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        assert!((parse_time("7d").unwrap() - (now - 7 * 86400)).abs() <= 1);
+        assert!(parse_time("yesterday").is_err());
+        assert!(parse_time("2026-01").is_err());
+    }
+
+    #[test]
+    fn test_filter_order() {
+        let log_file = FileEntry { relpath: "a.log".into(), ..Default::default() };
+        let filter = |args: &[&str]| {
+            let args = RunArgs::parse_ordered_from(["jbodncp", "download", "http://host", "/mnt", "--auth", "token"].iter().chain(args));
+            let SubCommand::Download(config) = args.cmd else { unreachable!() };
+            Filter { rules: config.filter_rules, ..Default::default() }
+        };
+        assert!(!filter(&["--exclude", "*.log", "--include", "a.*"]).matches(&log_file));
+        assert!(filter(&["--include", "a.*", "--exclude", "*.log"]).matches(&log_file));
+        assert!(filter(&["--exclude", "b", "--include", "a.*", "--exclude", "*"]).matches(&log_file));
+    }
 }
@@ -1,31 +1,98 @@
-use anyhow::{ Result, Context, ensure };
-use serde_json;
-use crate::filelist::FileEntry;
+use anyhow::{ Result, Context, ensure, bail, anyhow };
+use crate::filelist::{ FileEntry, EntryKind, ListLine, ListSummary, WalkError, WalkOptions, NDJSON, partial_path, segments_path };
 use crate::jbod;
-use crate::cli::DownloadConfig;
-use crate::disk_space::get_available_space;
-use std::path::PathBuf;
+use crate::cli::{ DownloadConfig, CompareMode };
+use crate::disk_space::{ get_available_space, preallocate };
+use crate::ratelimit::{ RateLimiter, LimitedReader };
+use crate::progress::{ Progress, Reporter, format_bytes };
+use crate::report::{ Report, FailedFile };
+use crate::errors::Failure;
+use crate::metadata::{ Ownership, apply_metadata };
+use crate::checksum::{ HashingWriter, CHECKSUM_HEADER, CHECKSUM_PENDING_HEADER, hash_file };
+use crate::compression::{ self, ACCEPT_COMPRESSED };
+use crate::filter::{ Filter, FilterRule, FILTERED_HEADER };
+use crate::mirror;
+use std::path::{ Path, PathBuf, Component };
 use log::*;
 use glob::glob;
 use regex::Regex;
 use rand::Rng;
 
-use std::fs::File;
+use std::fs::{ File, OpenOptions };
+use std::io::{ Read, Write, BufRead, BufReader };
+use std::os::unix::fs::{ FileExt, FileTypeExt, MetadataExt };
+use std::os::unix::ffi::OsStrExt;
+use std::ffi::CString;
 use std::collections::VecDeque;
-use std::sync::{ Arc, Mutex };
+use std::sync::{ Arc, Mutex, Condvar };
 use std::thread::JoinHandle;
-use std::collections::HashMap;
+use std::time::{ Duration, Instant };
+use std::collections::{ HashMap, HashSet, BTreeMap };
 
+#[derive(Default)]
 struct SharedState {
     queue: VecDeque<FileEntry>,
+    // the list is still arriving
+    listing: bool,
     downloaded: u64,
     errors: u64,
     files_seen: u64,
+    skipped: u64,
+    failed: Vec<(FileEntry, String)>,
+    unsupported: Vec<PathBuf>,
+    out_of_space: bool,
+
+    // hard link targets of the list, by relpath
+    links: HashMap<PathBuf, LinkTarget>,
+
+    // segmented downloads
+    segments: VecDeque<Segment>,
+    splitting: usize,
 
     // group by
     index: HashMap<String, PathBuf>,
 }
 
+enum LinkTarget {
+    // not downloaded yet, the hard links to it are created once it is
+    Pending(Vec<FileEntry>),
+    // downloaded to this path
+    Done(PathBuf),
+}
+
+impl SharedState {
+    // Hard links wait for their target to be downloaded, unless it already is
+    fn enqueue(&mut self, mut item: FileEntry) {
+        if item.linked {
+            self.links.insert(item.relpath.clone(), LinkTarget::Pending(vec![]));
+        }
+        if let Some(target) = &item.link {
+            match self.links.get_mut(target) {
+                Some(LinkTarget::Pending(followers)) => {
+                    followers.push(item);
+                    return;
+                }
+                Some(LinkTarget::Done(_)) => {},
+                // the target isn't part of the list, so it's downloaded as a regular file
+                None => item.link = None,
+            }
+        }
+        self.queue.push_back(item);
+    }
+    // The failed files are queued again, they are counted once more as they go through. Returns how many
+    fn requeue_failed(&mut self) -> usize {
+        if self.out_of_space {
+            return 0;
+        }
+        let failed = std::mem::take(&mut self.failed);
+        let count = failed.len();
+        self.files_seen -= count as u64;
+        self.errors -= count as u64;
+        self.queue.extend(failed.into_iter().map(|(item, _err)| item));
+        count
+    }
+}
+
 #[derive(Clone)]
 struct WorkerSettings {
     endpoint: String,
@@ -33,32 +100,152 @@ struct WorkerSettings {
     dst_paths: Vec<String>,
     dry_run: bool,
     group_by: Option<Regex>,
+    retries: u32,
+    retry_delay: Duration,
+    segment_threshold: u64,
+    segment_size: u64,
+    bwlimit: Option<Arc<RateLimiter>>,
+    progress: Arc<Progress>,
+    ownership: Ownership,
+    accept_encoding: &'static str,
+    compare: CompareMode,
 
     // group by (preload)
     index_preload: HashMap<String, PathBuf>,
 }
 
-enum DlStatus { NothingToDo, Completed }
+enum DlStatus { NothingToDo, Completed, Unsupported }
 
-pub fn run_client(args: DownloadConfig) -> Result<()> {
-    for dst_path in &args.dst_paths {
+/// Streams the list from the server, the entries which go through the filters are handed to on_entry as they arrive.
+/// Returns the number of entries filtered out and what the server couldn't list, the warnings are logged
+pub fn fetch_list(url: &str, auth: &str, compress: bool, prefix: Option<&Path>, filter: &Filter, mut on_entry: impl FnMut(FileEntry) -> Result<()>) -> Result<ListSummary> {
+    info!("Fetching file list");
+    // The filters are applied by the server as well, so that it sends only what's needed
+    let mut request = ureq::agent()
+        .get(&format!("{}/list", url))
+        .header("Authorization", &format!("Bearer {}", auth))
+        .header("Accept", &format!("{}, application/json", NDJSON))
+        .header("Accept-Encoding", if compress { ACCEPT_COMPRESSED } else { "identity" });
+    if let Some(prefix) = prefix {
+        request = request.query("prefix", prefix.to_string_lossy());
+    }
+    request = request.query_pairs(filter.rules.iter().map(FilterRule::query_param));
+    if let Some(min_size) = filter.min_size {
+        request = request.query("min_size", min_size.to_string());
+    }
+    if let Some(max_size) = filter.max_size {
+        request = request.query("max_size", max_size.to_string());
+    }
+    let mut response = request
+        .call().map_err(|err| match err {
+            ureq::Error::StatusCode(401 | 403) => anyhow!(Failure::AuthFailed),
+            err => anyhow!(err).context("Couldn't fetch the file list"),
+        })?;
+    let mut filtered = response.headers().get(FILTERED_HEADER).and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok()).unwrap_or(0);
+    let ndjson = response.headers().get("Content-Type").and_then(|v| v.to_str().ok()).is_some_and(|v| v.starts_with(NDJSON));
+    let content_encoding = content_encoding(&response);
+    let list = compression::decoder(response.body_mut().with_config().limit(u64::MAX).reader(), content_encoding.as_deref())?;
+    let list = BufReader::new(list);
+
+    // Older servers ignore the filters, so they are applied here too
+    let mut handle = |item: FileEntry| {
+        if prefix.is_some_and(|prefix| !item.relpath.starts_with(prefix)) || (!filter.is_empty() && !filter.matches(&item)) {
+            filtered += 1;
+            return Ok(());
+        }
+        on_entry(item)
+    };
+    if !ndjson {
+        let mut list: Vec<FileEntry> = serde_json::from_reader(list)?;
+        // Hard link targets aren't marked by older servers
+        let targets: HashSet<PathBuf> = list.iter().filter_map(|item| item.link.clone()).collect();
+        for item in &mut list {
+            item.linked = targets.contains(&item.relpath);
+        }
+        list.into_iter().try_for_each(handle)?;
+        return Ok(ListSummary { filtered, warnings: vec![] });
+    }
+
+    for line in list.lines() {
+        match serde_json::from_str(&line.context("Couldn't read the file list")?)? {
+            ListLine::Entry(item) => handle(item)?,
+            ListLine::End { end } => {
+                for warning in &end.warnings {
+                    warn!("The server couldn't list {}: {}", warning.path.display(), warning.error);
+                }
+                return Ok(ListSummary { filtered: filtered + end.filtered, warnings: end.warnings });
+            }
+        }
+    }
+    bail!("The file list is incomplete, the server has stopped in the middle of it")
+}
+
+// --rescan: the server reads its disks again before the list is fetched
+fn rescan_server(url: &str, auth: &str) -> Result<()> {
+    info!("Asking the server to rescan its disks");
+    let result = ureq::agent()
+        .post(&format!("{}/index/rescan", url))
+        .header("Authorization", &format!("Bearer {}", auth))
+        .send_empty();
+    match result {
+        Ok(_) => Ok(()),
+        // Without an index, the list is read from the disks anyway
+        Err(ureq::Error::StatusCode(404)) => {
+            warn!("The server doesn't keep an index, there is nothing to rescan");
+            Ok(())
+        }
+        Err(ureq::Error::StatusCode(401 | 403)) => Err(Failure::AuthFailed.into()),
+        Err(err) => Err(anyhow!(err).context("Couldn't rescan the server")),
+    }
+}
+
+pub fn check_dst_paths(dst_paths: &[String]) -> Result<()> {
+    for dst_path in dst_paths {
         ensure!(std::fs::exists(dst_path)?, "Directory not exists: {}", dst_path);
         ensure!(std::fs::metadata(dst_path)?.is_dir(), "Not a directory: {}", dst_path);
     }
+    Ok(())
+}
 
-    info!("Fetching file list");
-    let agent = ureq::agent();
-    let list = agent
-        .get(&format!("{}/list", args.url))
-        .header("Authorization", &format!("Bearer {}", args.auth))
-        .call()?.body_mut().with_config().limit(u64::MAX).read_to_string()?;
-    let queue: VecDeque<FileEntry> = serde_json::from_str(&list)?;
-    let files_matched = queue.len();
-
+pub fn run_client(args: DownloadConfig) -> Result<()> {
+    let started = Instant::now();
+    check_dst_paths(&args.dst_paths)?;
+    ensure!(args.retry_delay >= 0.0, "--retry-delay can't be negative");
+    ensure!(args.segment_size > 0, "--segment-size can't be zero");
     let group_by = args.group_by.as_deref().map(Regex::new).transpose().context("regex compilation")?;
+
+    // From now on, the report is written however the run ends
+    let mut report = Report::default();
+    let result = transfer(&args, group_by, &mut report);
+    if let Some(path) = &args.report {
+        report.duration_secs = started.elapsed().as_secs_f64();
+        report.error = result.as_ref().err().map(|err| format!("{:#}", err));
+        match report.write(path) {
+            Err(err) if result.is_err() => error!("{:#}", err),
+            written => written?,
+        }
+    }
+    result
+}
+
+// Fills the report as far as it gets
+fn transfer(args: &DownloadConfig, group_by: Option<Regex>, report: &mut Report) -> Result<()> {
+    let filter = Filter {
+        rules: args.filter_rules.clone(),
+        min_size: args.min_size,
+        max_size: args.max_size,
+        newer_than: args.newer_than,
+    };
+    if args.rescan {
+        rescan_server(&args.url, &args.auth)?;
+    }
+    let accept_encoding = if args.compress { ACCEPT_COMPRESSED } else { "identity" };
+    let progress = Arc::new(Progress::new(&args.dst_paths));
+
+    let walk = WalkOptions { threads: args.walk_threads, ..Default::default() };
     let index = if let Some(regex) = &group_by {
         info!("Building directory index (--group-by)");
-        jbod::index_by_regex(&args.dst_paths, regex)
+        jbod::index_by_regex(&args.dst_paths, regex, &walk)
     } else {
         HashMap::new()
     };
@@ -71,7 +258,7 @@ pub fn run_client(args: DownloadConfig) -> Result<()> {
         let dirs_str: Vec<String> = dirs.iter().map(|t| t.display().to_string()).collect();
         let dst_paths: Vec<_> = args.dst_paths.iter().map(PathBuf::from).collect();
         let lookup: HashMap<PathBuf, PathBuf> = dirs.into_iter().zip(dst_paths).collect();
-        let index = jbod::index_by_regex(&dirs_str, &group_by.clone().unwrap());
+        let index = jbod::index_by_regex(&dirs_str, &group_by.clone().unwrap(), &walk);
         let index_len = index.len();
         let translated: HashMap<String, PathBuf> = index.into_iter().filter_map(|(k, v)| lookup.get(&v).cloned().map(|new_v| (k, new_v))).collect();
         ensure!(translated.len() == index_len, "Translation failed");
@@ -80,37 +267,289 @@ pub fn run_client(args: DownloadConfig) -> Result<()> {
         HashMap::new()
     };
 
-    let shared_state = Arc::new(Mutex::new(SharedState { queue, downloaded: 0, errors: 0, files_seen: 0, index }));
-    let worker_settings = WorkerSettings { endpoint: args.url.to_string(), auth: args.auth.to_string(), dst_paths: args.dst_paths, dry_run: args.dry_run, group_by: group_by, index_preload };
+    let shared_state = Arc::new(Mutex::new(SharedState {
+        queue: VecDeque::new(),
+        listing: true,
+        downloaded: 0,
+        errors: 0,
+        files_seen: 0,
+        skipped: 0,
+        failed: vec![],
+        unsupported: vec![],
+        out_of_space: false,
+        links: HashMap::new(),
+        segments: VecDeque::new(),
+        splitting: 0,
+        index,
+    }));
+    let worker_settings = WorkerSettings {
+        endpoint: args.url.to_string(),
+        auth: args.auth.to_string(),
+        dst_paths: args.dst_paths.clone(),
+        dry_run: args.dry_run,
+        group_by,
+        retries: args.retries,
+        retry_delay: Duration::from_secs_f64(args.retry_delay),
+        segment_threshold: args.segment_threshold,
+        segment_size: args.segment_size,
+        bwlimit: args.bwlimit.clone().map(|schedule| Arc::new(RateLimiter::new(schedule))),
+        progress: progress.clone(),
+        ownership: Ownership::new(&args.uid_map, &args.gid_map),
+        accept_encoding,
+        compare: args.compare,
+        index_preload,
+    };
 
-    let mut workers: VecDeque<JoinHandle<()>> = VecDeque::new();
-    for _ in 0..args.threads {
-        let shared_state = shared_state.clone();
-        let worker_settings = worker_settings.clone();
-        workers.push_back(std::thread::spawn(move || {
-            Worker::new(shared_state, worker_settings).run();
-        }));
+    let reporter = (args.progress_interval > 0).then(|| Reporter::start(progress.clone(), Duration::from_secs(args.progress_interval)));
+    let wakeup = Arc::new(Condvar::new());
+    // The workers start as soon as the first entries arrive
+    let listing = std::thread::scope(|scope| {
+        let lister = scope.spawn(|| stream_list(args, &filter, &shared_state, &wakeup, &progress));
+        run_workers(&shared_state, &wakeup, &worker_settings, args.threads);
+        lister.join().unwrap()
+    });
+    let Listing { matched: files_matched, filtered: files_filtered, listed, warnings } = match listing {
+        Ok(listing) => listing,
+        Err(err) => {
+            if let Some(reporter) = reporter {
+                reporter.stop();
+            }
+            // What has been done before the list broke off
+            let state = shared_state.lock().unwrap();
+            *report = Report {
+                files_seen: state.files_seen,
+                downloaded: state.downloaded,
+                errors: state.errors,
+                skipped: state.skipped,
+                bytes_per_mount: progress.written().into_iter().collect(),
+                failed: state.failed.iter().map(|(item, error)| FailedFile { relpath: item.relpath.clone(), error: error.clone() }).collect(),
+                ..Default::default()
+            };
+            report.bytes_transferred = report.bytes_per_mount.values().sum();
+            return Err(err);
+        }
+    };
+
+    // Final pass: give the files that failed every retry one more chance, now that the rest of the job is done
+    let retried = shared_state.lock().unwrap().requeue_failed();
+    if retried > 0 {
+        info!("Retrying {} failed files", retried);
+        run_workers(&shared_state, &wakeup, &worker_settings, args.threads);
     }
-    while let Some(thread) = workers.pop_front() {
-        thread.join().unwrap();
+    if let Some(reporter) = reporter {
+        reporter.stop();
+    }
+
+    // Hard links to the files which couldn't be downloaded
+    let mut state = shared_state.lock().unwrap();
+    let orphans: Vec<FileEntry> = std::mem::take(&mut state.links).into_values()
+        .flat_map(|target| match target {
+            LinkTarget::Pending(followers) => followers,
+            LinkTarget::Done(_) => vec![],
+        })
+        .collect();
+    for item in orphans {
+        state.files_seen += 1;
+        state.errors += 1;
+        let error = format!("Hard link target wasn't downloaded: {}", item.link.as_ref().unwrap().display());
+        state.failed.push((item, error));
     }
 
-    let state = shared_state.lock().unwrap();
     if state.files_seen != files_matched as u64 {
         warn!("Some files were ignored. Files seen: {} matched: {}", state.files_seen, files_matched);
     }
+
+    // Mirror mode, only once everything is in place
+    let mut deleted = 0;
+    let mut delete_error = None;
+    if args.delete {
+        if state.errors > 0 || state.out_of_space || state.files_seen != files_matched as u64 {
+            warn!("Some files weren't transferred, so nothing is deleted");
+        } else if !warnings.is_empty() {
+            // What the server couldn't list would be taken for extraneous
+            warn!("The server couldn't list everything, so nothing is deleted");
+        } else {
+            let prefix = args.prefix.clone().unwrap_or_default();
+            match delete_extraneous(&worker_settings.dst_paths, &prefix, &listed, &filter, args.max_delete_percent, args.confirm_delete, args.dry_run) {
+                Ok(count) => deleted = count,
+                Err(err) => {
+                    error!("Deletion failed: {:#}", err);
+                    delete_error = Some(err);
+                }
+            }
+        }
+    }
     if state.errors > 0 {
         warn!("Some transfers were completed with errors");
     }
+    if !warnings.is_empty() {
+        warn!("The server couldn't list {} paths, whatever is below them hasn't been transferred", warnings.len());
+    }
+    if !state.unsupported.is_empty() {
+        warn!("{} special files (sockets, devices) weren't replicated", state.unsupported.len());
+    }
     if args.dry_run {
         warn!("Dry run requested, so no downloads actually performed");
     }
-    info!("Everything is done. Files seen: {} downloaded: {} errors: {}", state.files_seen, state.downloaded, state.errors);
+    info!("Everything is done. Files seen: {} downloaded: {} skipped: {} filtered out: {} deleted: {} errors: {}", state.files_seen, state.downloaded, state.skipped, files_filtered, deleted, state.errors);
+    let written: Vec<String> = worker_settings.progress.written().iter().map(|(path, bytes)| format!("{} {}", path, format_bytes(*bytes))).collect();
+    info!("Bytes written: {}", written.join(", "));
+
+    let bytes_per_mount: BTreeMap<String, u64> = worker_settings.progress.written().into_iter().collect();
+    *report = Report {
+        files_seen: state.files_seen,
+        downloaded: state.downloaded,
+        errors: state.errors,
+        skipped: state.skipped,
+        filtered: files_filtered as u64,
+        deleted,
+        bytes_transferred: bytes_per_mount.values().sum(),
+        bytes_per_mount,
+        unsupported: state.unsupported.clone(),
+        list_warnings: warnings.clone(),
+        failed: state.failed.iter().map(|(item, error)| FailedFile { relpath: item.relpath.clone(), error: error.clone() }).collect(),
+        ..Default::default()
+    };
+
+    if state.out_of_space {
+        return Err(Failure::OutOfSpace.into());
+    }
+    if state.errors > 0 || state.files_seen != files_matched as u64 || !warnings.is_empty() {
+        return Err(Failure::Partial.into());
+    }
+    if let Some(err) = delete_error {
+        return Err(err);
+    }
     Ok(())
 }
 
+// --delete: the whole plan is always shown first, and only carried out with --confirm-delete if it doesn't go over the threshold
+fn delete_extraneous(dst_paths: &[String], prefix: &Path, listed: &HashSet<PathBuf>, filter: &Filter, max_percent: f64, confirmed: bool, dry_run: bool) -> Result<u64> {
+    info!("Looking for files to delete (--delete)");
+    let plan = mirror::plan_deletions(dst_paths, prefix, listed, filter)?;
+    mirror::delete(&plan, listed, true)?;
+    info!("{} files aren't on the server and {} are duplicates on other disks, {:.1}% of the destination", plan.extraneous.len(), plan.duplicates.len(), plan.percent());
+    if plan.percent() > max_percent {
+        return Err(Failure::TooManyDeletions.into());
+    }
+    if plan.len() == 0 || dry_run {
+        return Ok(0);
+    }
+    if !confirmed {
+        warn!("Nothing has been deleted, run again with --confirm-delete to delete the files above");
+        return Ok(0);
+    }
+    mirror::delete(&plan, listed, false)
+}
+
+// What the lister has passed on to the workers
+struct Listing {
+    matched: usize,
+    filtered: usize,
+    // relpaths of the list, for --delete only
+    listed: HashSet<PathBuf>,
+    // what the server couldn't list
+    warnings: Vec<WalkError>,
+}
+
+// Queues the entries of the list as they arrive, the queue is dropped if the list can't be fetched in full
+// Entries queued ahead of the workers before the list is paused
+const LIST_QUEUE_ENTRIES: usize = 100_000;
+
+fn stream_list(args: &DownloadConfig, filter: &Filter, state: &Mutex<SharedState>, wakeup: &Condvar, progress: &Progress) -> Result<Listing> {
+    let mut matched = 0;
+    let mut filtered = 0;
+    let mut listed = HashSet::new();
+    // With --delete, the entries filtered out by size or mtime are still on the server, so they have to be listed
+    let path_rules = filter.path_rules();
+    let sent_filter = if args.delete { &path_rules } else { filter };
+    let result = fetch_list(&args.url, &args.auth, args.compress, args.prefix.as_deref(), sent_filter, |item| {
+        if args.delete {
+            listed.insert(item.relpath.clone());
+            if !filter.matches(&item) {
+                filtered += 1;
+                return Ok(());
+            }
+        }
+        if args.compare == CompareMode::Mtime && item.kind.is_file() && item.mtime.is_none() {
+            bail!("--compare mtime needs the modification times, which this server doesn't send");
+        }
+        matched += 1;
+        let mut state = state.lock().unwrap();
+        // The workers make room as they go, so that the list isn't held in memory
+        while state.queue.len() >= LIST_QUEUE_ENTRIES && !state.out_of_space {
+            state = wakeup.wait(state).unwrap();
+        }
+        // Nobody is left to download it
+        if state.out_of_space {
+            return Ok(());
+        }
+        // hard links aren't part of the byte total
+        let is_link = item.link.as_ref().is_some_and(|target| state.links.contains_key(target));
+        progress.add_total(1, if is_link { 0 } else { item.size });
+        state.enqueue(item);
+        drop(state);
+        wakeup.notify_one();
+        Ok(())
+    });
+
+    let mut state = state.lock().unwrap();
+    state.listing = false;
+    if result.is_err() {
+        state.queue.clear();
+    }
+    drop(state);
+    wakeup.notify_all();
+    let ListSummary { filtered: filtered_by_server, warnings } = result?;
+    Ok(Listing { matched, filtered: filtered + filtered_by_server, listed, warnings })
+}
+
+fn run_workers(shared_state: &Arc<Mutex<SharedState>>, wakeup: &Arc<Condvar>, worker_settings: &WorkerSettings, threads: u16) {
+    let mut workers: VecDeque<JoinHandle<()>> = VecDeque::new();
+    for _ in 0..threads {
+        let shared_state = shared_state.clone();
+        let wakeup = wakeup.clone();
+        let worker_settings = worker_settings.clone();
+        workers.push_back(std::thread::spawn(move || {
+            Worker::new(shared_state, wakeup, worker_settings).run();
+        }));
+    }
+    while let Some(thread) = workers.pop_front() {
+        thread.join().unwrap();
+    }
+}
+
+// A large file being downloaded by several workers at once, see --segment-threshold
+struct SegmentedFile {
+    item: FileEntry,
+    download_url: String,
+    dst_path: PathBuf,
+    tmp_path: PathBuf,
+    // the segments written into tmp_path so far, see read_done_segments
+    segments_path: PathBuf,
+    progress: Mutex<SegmentProgress>,
+}
+
+struct SegmentProgress {
+    remaining: usize,
+    failed: bool,
+    checksum: Option<ExpectedChecksum>,
+}
+
+struct Segment {
+    file: Arc<SegmentedFile>,
+    start: u64,
+    end: u64,
+}
+
+enum Job {
+    File(FileEntry),
+    Segment(Segment),
+}
+
 struct Worker {
     state: Arc<Mutex<SharedState>>,
+    wakeup: Arc<Condvar>,
     settings: WorkerSettings,
     agent: ureq::Agent,
 }
@@ -118,40 +557,166 @@ struct Worker {
 type AbsPath = PathBuf;
 
 impl Worker {
-    fn new(state: Arc<Mutex<SharedState>>, settings: WorkerSettings) -> Worker {
-        Worker { state, settings, agent: ureq::agent() }
+    fn new(state: Arc<Mutex<SharedState>>, wakeup: Arc<Condvar>, settings: WorkerSettings) -> Worker {
+        // Error statuses are handled by ourselves, since some of them (e.g. 429) carry meaningful headers
+        let agent = ureq::Agent::config_builder().http_status_as_error(false).build().into();
+        Worker { state, wakeup, settings, agent }
     }
     fn run(&mut self) {
-       while let Some(item) = self.next_item() {
-           let download_url = format!("{}/download/{}", &self.settings.endpoint, item.relpath.display());
-           let Some(dst_path) = self.dst_file_path(&item) else {
-               error!("No available disks left");
-               return;
-           };
-
-           let result = self.download(&download_url, &dst_path, item.size);
-           if let Err(err) = &result {
-               error!("File download failed: {} {:#}", dst_path.display(), err);
-           }
-
-           let mut state = self.state.lock().unwrap();
-           state.files_seen += 1;
-           match result {
-               Ok(DlStatus::Completed) => state.downloaded+=1,
-               Ok(DlStatus::NothingToDo) => {},
-               Err(_) => state.errors+=1,
-           }
-       }
-    }
-    fn download(&self, download_url: &str, dst_path: &PathBuf, expected_size: u64) -> Result<DlStatus> {
-        let exists = std::fs::exists(&dst_path).unwrap_or(false);
-        if exists && std::fs::metadata(&dst_path)?.len() == expected_size {
-            info!("File already completed: {}", dst_path.display());
+        while let Some(job) = self.next_job() {
+            match job {
+                Job::File(item) => {
+                    let segmented = self.is_segmented(&item);
+                    let keep_going = self.process_file(item, segmented);
+                    if segmented {
+                        self.state.lock().unwrap().splitting -= 1;
+                        self.wakeup.notify_all();
+                    }
+                    if !keep_going {
+                        return;
+                    }
+                }
+                Job::Segment(segment) => self.process_segment(segment),
+            }
+        }
+    }
+    fn process_file(&mut self, item: FileEntry, segmented: bool) -> bool {
+        if let Err(err) = check_relpath(&item.relpath).and_then(|()| item.link.as_deref().map_or(Ok(()), check_relpath)) {
+            let relpath = item.relpath.clone();
+            self.record_result(item, &relpath, Err(err));
+            return true;
+        }
+        // A hard link whose target had already been downloaded when it arrived, see SharedState::enqueue
+        if let Some(link) = &item.link {
+            let target = match self.state.lock().unwrap().links.get(link) {
+                Some(LinkTarget::Done(target)) => target.clone(),
+                _ => unreachable!("hard links are queued once their target is downloaded"),
+            };
+            self.link_to(item, &target);
+            return true;
+        }
+        let download_url = format!("{}/download/{}", &self.settings.endpoint, item.relpath.display());
+        let Some(dst_path) = self.dst_file_path(&item) else {
+            error!("No available disks left");
+            self.state.lock().unwrap().out_of_space = true;
+            self.wakeup.notify_all();
+            return false;
+        };
+
+        let result = if let Err(err) = check_ancestors(&item.relpath, &dst_path) {
+            Err(err)
+        } else if !item.kind.is_file() {
+            self.replicate(&item, &dst_path)
+        } else if segmented {
+            match self.with_retries(&dst_path, || self.start_segmented(&item, &download_url, &dst_path)) {
+                // The worker which completes the last segment accounts for the file
+                Ok(None) => return true,
+                Ok(Some(status)) => Ok(status),
+                Err(err) => Err(err),
+            }
+        } else {
+            self.with_retries(&dst_path, || self.download(&download_url, &dst_path, &item))
+        };
+        self.record_result(item, &dst_path, result);
+        true
+    }
+    fn record_result(&self, item: FileEntry, dst_path: &Path, result: Result<DlStatus>) {
+        if let Err(err) = &result {
+            error!("File download failed: {} {:#}", dst_path.display(), err);
+        }
+        if matches!(result, Ok(DlStatus::Completed | DlStatus::NothingToDo)) {
+            self.link_followers(&item, dst_path);
+        }
+
+        match result {
+            Ok(DlStatus::Completed | DlStatus::Unsupported) => self.settings.progress.file_done(),
+            Ok(DlStatus::NothingToDo) => {
+                self.settings.progress.file_done();
+                // hard links aren't part of the byte total
+                if item.link.is_none() {
+                    self.settings.progress.skipped(item.size);
+                }
+            }
+            Err(_) => {},
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.files_seen += 1;
+        match result {
+            Ok(DlStatus::Completed) => state.downloaded+=1,
+            Ok(DlStatus::NothingToDo) => state.skipped+=1,
+            Ok(DlStatus::Unsupported) => state.unsupported.push(item.relpath),
+            Err(err) => {
+                state.errors+=1;
+                state.failed.push((item, format!("{:#}", err)));
+            }
+        }
+    }
+    fn link_followers(&self, item: &FileEntry, dst_path: &Path) {
+        if !item.linked {
+            return;
+        }
+        let previous = self.state.lock().unwrap().links.insert(item.relpath.clone(), LinkTarget::Done(dst_path.to_path_buf()));
+        if let Some(LinkTarget::Pending(followers)) = previous {
+            for follower in followers {
+                self.link_to(follower, dst_path);
+            }
+        }
+    }
+    // Hard links go onto the same mount point as their target
+    fn link_to(&self, follower: FileEntry, target: &Path) {
+        let depth = follower.link.as_ref().map_or(0, |link| link.components().count());
+        let Some(mount_point) = target.ancestors().nth(depth) else {
+            return;
+        };
+        let link_path = mount_point.join(&follower.relpath);
+        let result = check_relpath(&follower.relpath)
+            .and_then(|()| check_ancestors(&follower.relpath, &link_path))
+            .and_then(|()| self.hard_link(target, &link_path));
+        self.record_result(follower, &link_path, result);
+    }
+    fn hard_link(&self, target: &Path, link_path: &Path) -> Result<DlStatus> {
+        let target_metadata = std::fs::metadata(target);
+        let same_inode = |m: &std::fs::Metadata| target_metadata.as_ref().is_ok_and(|t| (t.dev(), t.ino()) == (m.dev(), m.ino()));
+        if std::fs::symlink_metadata(link_path).is_ok_and(|m| same_inode(&m)) {
+            debug!("Hard link already exists: {}", link_path.display());
             return Ok(DlStatus::NothingToDo);
         }
 
-        info!("Downloading URL: {} => {}", download_url, dst_path.display());
+        debug!("Creating hard link: {} => {}", link_path.display(), target.display());
+        if self.settings.dry_run {
+            return Ok(DlStatus::Completed);
+        }
+
+        if let Some(parent) = link_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Created aside and renamed, so that an existing copy gets replaced atomically
+        let tmp_path = partial_path(link_path);
+        let _ = std::fs::remove_file(&tmp_path);
+        std::fs::hard_link(target, &tmp_path)?;
+        std::fs::rename(&tmp_path, link_path)?;
+        Ok(DlStatus::Completed)
+    }
+    // Recreates directories, symlinks and FIFOs, which don't need any data to be downloaded
+    fn replicate(&self, item: &FileEntry, dst_path: &Path) -> Result<DlStatus> {
+        let existing = std::fs::symlink_metadata(dst_path).ok();
+        let up_to_date = match (item.kind, &existing) {
+            (EntryKind::Dir, Some(m)) => m.is_dir(),
+            (EntryKind::Symlink, Some(m)) => m.is_symlink() && std::fs::read_link(dst_path).ok() == item.target,
+            (EntryKind::Fifo, Some(m)) => m.file_type().is_fifo(),
+            _ => false,
+        };
+        if up_to_date {
+            debug!("Already exists: {}", dst_path.display());
+            return Ok(DlStatus::NothingToDo);
+        }
+        if matches!(item.kind, EntryKind::Socket | EntryKind::BlockDevice | EntryKind::CharDevice) {
+            warn!("Can't replicate a special file ({:?}): {}", item.kind, item.relpath.display());
+            return Ok(DlStatus::Unsupported);
+        }
 
+        debug!("Creating {:?}: {}", item.kind, dst_path.display());
         if self.settings.dry_run {
             return Ok(DlStatus::Completed);
         }
@@ -159,33 +724,329 @@ impl Worker {
         if let Some(parent) = dst_path.parent() {
             std::fs::create_dir_all(parent)?;
         }
-
-        let mut response = self.agent.get(download_url)
+        match existing {
+            Some(m) if m.is_dir() => std::fs::remove_dir(dst_path)?,
+            Some(_) => std::fs::remove_file(dst_path)?,
+            None => {},
+        }
+        match item.kind {
+            EntryKind::Dir => std::fs::create_dir(dst_path)?,
+            EntryKind::Symlink => {
+                let target = item.target.as_ref().context("Symlink target is missing")?;
+                std::os::unix::fs::symlink(target, dst_path)?;
+            }
+            EntryKind::Fifo => {
+                let c_path = CString::new(dst_path.as_os_str().as_bytes())?;
+                if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+                    return Err(std::io::Error::last_os_error()).context("mkfifo");
+                }
+            }
+            kind => bail!("Unexpected entry type: {:?}", kind),
+        }
+        apply_metadata(dst_path, item, &self.settings.ownership)?;
+        Ok(DlStatus::Completed)
+    }
+    fn with_retries<T>(&self, dst_path: &Path, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(err) if err.downcast_ref::<ServerBusy>().is_some() => {
+                    let ServerBusy(delay) = err.downcast().unwrap();
+                    let delay = delay.mul_f64(rand::rng().random_range(1.0..2.0));
+                    debug!("Server is busy, retrying in {:.1}s: {}", delay.as_secs_f64(), dst_path.display());
+                    std::thread::sleep(delay);
+                }
+                Err(err) if attempt < self.settings.retries => {
+                    let delay = backoff_delay(self.settings.retry_delay, attempt);
+                    attempt += 1;
+                    warn!("File download failed: {} {:#}, retry {}/{} in {:.1}s", dst_path.display(), err, attempt, self.settings.retries, delay.as_secs_f64());
+                    std::thread::sleep(delay);
+                }
+                result => return result,
+            }
+        }
+    }
+    // The rate is limited on the bytes on the wire, while the progress counts the decompressed ones
+    fn body_reader<'a>(&self, response: &'a mut http::Response<ureq::Body>, dst_path: &Path) -> Result<impl Read + 'a> {
+        let content_encoding = content_encoding(response);
+        let body = LimitedReader::new(response.body_mut().as_reader(), self.settings.bwlimit.clone());
+        let body = compression::decoder(body, content_encoding.as_deref())?;
+        Ok(self.settings.progress.reader(body, dst_path))
+    }
+    // A file of another size is never up to date, --compare decides about the rest
+    fn is_up_to_date(&self, item: &FileEntry, dst_path: &Path) -> Result<bool> {
+        let Ok(metadata) = std::fs::metadata(dst_path) else {
+            return Ok(false);
+        };
+        if metadata.len() != item.size {
+            return Ok(false);
+        }
+        Ok(match self.settings.compare {
+            CompareMode::Size => true,
+            CompareMode::Mtime => item.mtime == Some(metadata.mtime()) && item.mtime_nsec.is_none_or(|nsec| i64::from(nsec) == metadata.mtime_nsec()),
+            CompareMode::Checksum => {
+                let expected = fetch_checksum(&self.agent, &self.settings.endpoint, &self.settings.auth, &item.relpath)?;
+                hash_file(dst_path)? == expected
+            }
+        })
+    }
+    // Sparse files are downloaded in segments as well, which cover their data extents only
+    fn is_segmented(&self, item: &FileEntry) -> bool {
+        item.kind.is_file() && (item.sparse || self.settings.segment_threshold > 0 && item.size > self.settings.segment_threshold)
+    }
+    fn fetch_extents(&self, item: &FileEntry) -> Result<Vec<(u64, u64)>> {
+        let url = format!("{}/extents/{}", &self.settings.endpoint, item.relpath.display());
+        let mut response = self.agent.get(&url)
             .header("Authorization", &format!("Bearer {}", self.settings.auth))
             .call().context("HTTP Request failed")?;
         ensure!(response.status() == 200, "Wrong response status: {}", response.status());
+        Ok(serde_json::from_str(&response.body_mut().read_to_string()?)?)
+    }
+    // Preallocates the partial file and queues the segments it lacks, returns None if there's something to download
+    fn start_segmented(&self, item: &FileEntry, download_url: &str, dst_path: &Path) -> Result<Option<DlStatus>> {
+        if self.is_up_to_date(item, dst_path)? {
+            debug!("File already completed: {}", dst_path.display());
+            return Ok(Some(DlStatus::NothingToDo));
+        }
+
+        let extents = if item.sparse { self.fetch_extents(item)? } else { vec![(0, item.size)] };
+        let segment_size = self.settings.segment_size;
+        let ranges: Vec<(u64, u64)> = extents.iter()
+            .flat_map(|&(offset, len)| (offset..offset + len).step_by(segment_size as usize).map(move |start| (start, (start + segment_size).min(offset + len) - 1)))
+            .collect();
+        debug!("Downloading URL in {} segments: {} => {}", ranges.len(), download_url, dst_path.display());
+
+        if self.settings.dry_run {
+            return Ok(Some(DlStatus::Completed));
+        }
+
+        if let Some(parent) = dst_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // The existing copy stays in place until the new one is renamed over it
+        let tmp_path = partial_path(dst_path);
+        let segments_path = segments_path(dst_path);
+        let version = segments_version(item);
+        let done = match std::fs::metadata(&tmp_path) {
+            Ok(m) if m.len() == item.size => read_done_segments(&segments_path, &version),
+            _ => HashSet::new(),
+        };
+        if done.is_empty() {
+            let tmp_file = File::create(&tmp_path)?;
+            if item.sparse {
+                // The holes are left as they are, so only the data extents get allocated
+                tmp_file.set_len(item.size)?;
+            } else {
+                preallocate(&tmp_file, item.size)?;
+            }
+            std::fs::write(&segments_path, format!("{version}\n"))?;
+        } else {
+            debug!("Resuming a segmented download, {} segments are already done: {}", done.len(), dst_path.display());
+        }
+        let ranges: Vec<(u64, u64)> = ranges.into_iter().filter(|range| !done.contains(range)).collect();
+        let left: u64 = ranges.iter().map(|(start, end)| end + 1 - start).sum();
+        self.settings.progress.skipped(item.size - left);
 
-        let mut reader = response.body_mut().as_reader();
-        let mut file = File::create(dst_path)?;
-        std::io::copy(&mut reader, &mut file)?;
+        let file = Arc::new(SegmentedFile {
+            item: item.clone(),
+            download_url: download_url.to_owned(),
+            dst_path: dst_path.to_owned(),
+            tmp_path,
+            segments_path,
+            progress: Mutex::new(SegmentProgress { remaining: ranges.len(), failed: false, checksum: None }),
+        });
+        if ranges.is_empty() {
+            return self.finish_segmented(&file, None).map(Some);
+        }
+        let segments = ranges.into_iter().map(|(start, end)| Segment { file: file.clone(), start, end });
+        self.state.lock().unwrap().segments.extend(segments);
+        self.wakeup.notify_all();
+        Ok(None)
+    }
+    fn process_segment(&mut self, segment: Segment) {
+        let file = &segment.file;
+
+        // Once a segment has failed for good, the rest of the file isn't worth downloading
+        if !file.progress.lock().unwrap().failed {
+            let result = self.with_retries(&file.dst_path, || self.download_segment(&segment));
+            let mut progress = file.progress.lock().unwrap();
+            match result {
+                Ok(checksum) => {
+                    // Segments served before the server knew the checksum only tell it's pending
+                    progress.checksum = match (progress.checksum.take(), checksum) {
+                        (Some(ExpectedChecksum::Known(known)), _) | (_, Some(ExpectedChecksum::Known(known))) => Some(ExpectedChecksum::Known(known)),
+                        (current, checksum) => current.or(checksum),
+                    };
+                    // Only a missed chance to resume
+                    if let Err(err) = record_done_segment(&file.segments_path, &segment) {
+                        warn!("Couldn't record a downloaded segment: {} {}", file.segments_path.display(), err);
+                    }
+                }
+                Err(err) => {
+                    error!("Segment download failed: {} bytes {}-{} {:#}", file.dst_path.display(), segment.start, segment.end, err);
+                    progress.failed = true;
+                }
+            }
+        }
+
+        let mut progress = file.progress.lock().unwrap();
+        progress.remaining -= 1;
+        if progress.remaining > 0 {
+            return;
+        }
+        let result = if progress.failed {
+            Err(anyhow!("Some segments couldn't be downloaded"))
+        } else {
+            let checksum = progress.checksum.clone();
+            drop(progress);
+            self.finish_segmented(file, checksum)
+        };
+        self.record_result(file.item.clone(), &file.dst_path, result);
+    }
+    fn download_segment(&self, segment: &Segment) -> Result<Option<ExpectedChecksum>> {
+        let file = &segment.file;
+        debug!("Downloading segment {}-{}: {}", segment.start, segment.end, file.dst_path.display());
+
+        let mut response = self.agent.get(&file.download_url)
+            .header("Authorization", &format!("Bearer {}", self.settings.auth))
+            .header("Range", &format!("bytes={}-{}", segment.start, segment.end))
+            .header("If-Range", &file_etag(&file.item))
+            .header("Accept-Encoding", self.settings.accept_encoding)
+            .call().context("HTTP Request failed")?;
+        check_busy(&response)?;
+        ensure!(response.status() != 200, "Changed on the server since it has been listed");
+        ensure!(response.status() == 206, "Wrong response status: {}", response.status());
+        let content_range = response.headers().get("Content-Range").and_then(|v| v.to_str().ok()).unwrap_or("");
+        ensure!(content_range.starts_with(&format!("bytes {}-{}/", segment.start, segment.end)), "Unexpected Content-Range: {}", content_range);
+        let checksum = expected_checksum(&response);
+
+        let dst = OpenOptions::new().write(true).open(&file.tmp_path)?;
+        let mut reader = self.body_reader(&mut response, &file.dst_path)?;
+        let mut buf = vec![0u8; 1 << 20];
+        let mut pos = segment.start;
+        loop {
+            let len = reader.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            dst.write_all_at(&buf[..len], pos)?;
+            pos += len as u64;
+        }
+        ensure!(pos == segment.end + 1, "Segment size check failed: {} bytes expected, {} received", segment.end + 1 - segment.start, pos - segment.start);
+        // On disk before it's recorded as done
+        dst.sync_data()?;
+
+        Ok(checksum)
+    }
+    fn finish_segmented(&self, file: &SegmentedFile, expected_checksum: Option<ExpectedChecksum>) -> Result<DlStatus> {
+        let file_size = std::fs::metadata(&file.tmp_path)?.len();
+        ensure!(file_size == file.item.size, "Filesize check failed: {} bytes expected, {file_size} received", file.item.size);
+
+        if let Some(expected_checksum) = self.resolve_checksum(&file.item, expected_checksum)? {
+            let checksum = hash_file(&file.tmp_path)?;
+            if checksum != expected_checksum {
+                std::fs::remove_file(&file.segments_path)?;
+                std::fs::remove_file(&file.tmp_path)?;
+                bail!("Checksum mismatch: {expected_checksum} expected, {checksum} received");
+            }
+        }
+
+        apply_metadata(&file.tmp_path, &file.item, &self.settings.ownership)?;
+        std::fs::rename(&file.tmp_path, &file.dst_path)?;
+        std::fs::remove_file(&file.segments_path)?;
+        Ok(DlStatus::Completed)
+    }
+    fn download(&self, download_url: &str, dst_path: &PathBuf, item: &FileEntry) -> Result<DlStatus> {
+        let expected_size = item.size;
+        if self.is_up_to_date(item, dst_path)? {
+            debug!("File already completed: {}", dst_path.display());
+            return Ok(DlStatus::NothingToDo);
+        }
 
-        let file_size = std::fs::metadata(&dst_path)?.len();
+        debug!("Downloading URL: {} => {}", download_url, dst_path.display());
+
+        if self.settings.dry_run {
+            return Ok(DlStatus::Completed);
+        }
+
+        if let Some(parent) = dst_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // The file is written under a temporary name, a mismatching one (e.g. an older version) stays in place until the rename.
+        // It's a complete copy of something else, so only a partial file left by an interrupted transfer is resumed
+        let tmp_path = partial_path(dst_path);
+        let partial_size = std::fs::metadata(&tmp_path).map(|m| m.len()).ok();
+        let offset = partial_size.filter(|size| *size < expected_size).unwrap_or(0);
+        let mut request = self.agent.get(download_url)
+            .header("Authorization", &format!("Bearer {}", self.settings.auth))
+            .header("Accept-Encoding", self.settings.accept_encoding);
+        // The whole file comes back instead if it has changed since it has been listed
+        if offset > 0 {
+            request = request.header("Range", &format!("bytes={offset}-")).header("If-Range", &file_etag(item));
+        }
+        let mut response = request.call().context("HTTP Request failed")?;
+        check_busy(&response)?;
+
+        let mut writer = match response.status().as_u16() {
+            200 => HashingWriter::new(File::create(&tmp_path)?),
+            206 => {
+                let content_range = response.headers().get("Content-Range").and_then(|v| v.to_str().ok()).unwrap_or("");
+                ensure!(content_range.starts_with(&format!("bytes {offset}-")), "Unexpected Content-Range: {}", content_range);
+                debug!("Resuming download from byte {}: {}", offset, dst_path.display());
+                self.settings.progress.skipped(offset);
+                let file = OpenOptions::new().read(true).append(true).open(&tmp_path)?;
+                let mut writer = HashingWriter::new(file);
+                std::io::copy(&mut (&writer.inner).take(offset), &mut writer.checksum)?;
+                writer
+            }
+            status => bail!("Wrong response status: {}", status),
+        };
+        let expected_checksum = expected_checksum(&response);
+
+        let mut reader = self.body_reader(&mut response, dst_path)?;
+        std::io::copy(&mut reader, &mut writer)?;
+
+        let file_size = std::fs::metadata(&tmp_path)?.len();
         ensure!(file_size == expected_size,  "Filesize check failed: {expected_size} bytes expected, {file_size} received");
 
+        if let Some(expected_checksum) = self.resolve_checksum(item, expected_checksum)? {
+            let checksum = writer.checksum.finish();
+            if checksum != expected_checksum {
+                // Can't tell which part is broken, so the next attempt starts from scratch
+                std::fs::remove_file(&tmp_path)?;
+                bail!("Checksum mismatch: {expected_checksum} expected, {checksum} received");
+            }
+        }
+
+        apply_metadata(&tmp_path, item, &self.settings.ownership)?;
+        std::fs::rename(&tmp_path, dst_path)?;
         Ok(DlStatus::Completed)
     }
+    // The checksum the complete file should have, None if the server doesn't keep them
+    fn resolve_checksum(&self, item: &FileEntry, expected: Option<ExpectedChecksum>) -> Result<Option<String>> {
+        match expected {
+            Some(ExpectedChecksum::Known(checksum)) => Ok(Some(checksum)),
+            Some(ExpectedChecksum::Pending) => fetch_checksum(&self.agent, &self.settings.endpoint, &self.settings.auth, &item.relpath).map(Some),
+            None => Ok(None),
+        }
+    }
     fn dst_file_path(&mut self, item: &FileEntry) -> Option<AbsPath> {
         // File already exists in one of partitions, so just return it's absolute path
         if let Some(abs_path) = jbod::find_file(&self.settings.dst_paths, &item.relpath) {
             return Some(abs_path);
         }
 
+        // An interrupted download is resumed at the same place
+        if let Some(abs_path) = jbod::find_partial(&self.settings.dst_paths, &item.relpath) {
+            return Some(abs_path);
+        }
+
         // --group-by and --group-by-preload specified
         let group_key: Option<String> = self.settings.group_by.as_ref().and_then(|regex| Self::make_group_key(regex, &item.relpath));
-        if let Some(group_key) = &group_key {
-            if let Some(base) = self.settings.index_preload.get(group_key) {
-                return Some(base.join(&item.relpath));
-            }
+        if let Some(base) = group_key.as_ref().and_then(|key| self.settings.index_preload.get(key)) {
+            return Some(base.join(&item.relpath));
         }
 
         let disk_spaces: Vec<_> = self.settings.dst_paths.iter()
@@ -196,10 +1057,8 @@ impl Worker {
         let mut state = self.state.lock().unwrap();
 
         // If --group-by is specified and this relpath is already indexed, use the same partition
-        if let Some(group_key) = &group_key {
-            if let Some(base) = state.index.get(group_key) {
-                return Some(base.join(&item.relpath));
-            }
+        if let Some(base) = group_key.as_ref().and_then(|key| state.index.get(key)) {
+            return Some(base.join(&item.relpath));
         }
 
         // Roll dice if nothing above worked
@@ -212,19 +1071,165 @@ impl Worker {
 
         Some(dst_path.join(&item.relpath))
     }
-    fn make_group_key(regex: &Regex, relpath: &PathBuf) -> Option<String> {
+    fn make_group_key(regex: &Regex, relpath: &Path) -> Option<String> {
         let filename = relpath.file_name().unwrap().to_string_lossy();
         let captures = regex.captures(&filename)?;
         let key: &str = &captures[if captures.len() > 1 { 1 } else { 0 }];
         Some(key.into())
     }
-    fn next_item(&mut self) -> Option<FileEntry> {
-        self.state.lock().unwrap().queue.pop_front()
+    fn next_job(&mut self) -> Option<Job> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            // Segments go first, so that the files already started get finished sooner
+            if let Some(segment) = state.segments.pop_front() {
+                return Some(Job::Segment(segment));
+            }
+            if let Some(item) = state.queue.pop_front() {
+                // The list is paused once the queue is full, see stream_list
+                if state.listing && state.queue.len() == LIST_QUEUE_ENTRIES / 2 {
+                    self.wakeup.notify_all();
+                }
+                if self.is_segmented(&item) {
+                    state.splitting += 1;
+                }
+                return Some(Job::File(item));
+            }
+            // Somebody is about to split a large file into segments, or more entries are on their way, so wait rather than exit
+            if state.splitting == 0 && !state.listing {
+                return None;
+            }
+            state = self.wakeup.wait(state).unwrap();
+        }
+    }
+}
+
+/// The server has asked to come back later (429 Too Many Requests), this doesn't count as a failed attempt
+#[derive(Debug)]
+struct ServerBusy(Duration);
+
+impl std::fmt::Display for ServerBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Server is busy, retry in {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ServerBusy {}
+
+// What a download response tells about the checksum of the whole file
+#[derive(Clone, Debug, PartialEq)]
+enum ExpectedChecksum {
+    Known(String),
+    // to be asked for once the file is complete, see CHECKSUM_PENDING_HEADER
+    Pending,
+}
+
+fn expected_checksum(response: &http::Response<ureq::Body>) -> Option<ExpectedChecksum> {
+    match response.headers().get(CHECKSUM_HEADER).and_then(|v| v.to_str().ok()) {
+        Some(checksum) => Some(ExpectedChecksum::Known(checksum.to_owned())),
+        None => response.headers().contains_key(CHECKSUM_PENDING_HEADER).then_some(ExpectedChecksum::Pending),
+    }
+}
+
+fn content_encoding(response: &http::Response<ureq::Body>) -> Option<String> {
+    response.headers().get("Content-Encoding").and_then(|v| v.to_str().ok()).map(str::to_owned)
+}
+
+/// Checksum of a file on the server, see --compare checksum and verify --checksum.
+/// The server reads the file within the stream limits of the client, so this waits for a free stream
+pub fn fetch_checksum(agent: &ureq::Agent, endpoint: &str, auth: &str, relpath: &Path) -> Result<String> {
+    loop {
+        let response = agent.get(&format!("{}/checksum/{}", endpoint, relpath.display()))
+            .header("Authorization", &format!("Bearer {}", auth))
+            .call();
+        let mut response = match response {
+            // Agents which take error statuses for errors don't tell how long to wait
+            Err(ureq::Error::StatusCode(429)) => {
+                std::thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+            response => response.context("HTTP Request failed")?,
+        };
+        if let Err(err) = check_busy(&response) {
+            let ServerBusy(delay) = err.downcast().unwrap();
+            std::thread::sleep(delay);
+            continue;
+        }
+        ensure!(response.status() == 200, "Wrong response status: {}", response.status());
+        return Ok(response.body_mut().read_to_string()?.trim().to_owned());
+    }
+}
+
+// The ETag the server gives the listed version of the file (size and mtime), for If-Range
+fn file_etag(item: &FileEntry) -> String {
+    // Before the epoch counts as 0 there too
+    let mtime = u128::try_from(item.mtime.unwrap_or(0)).map(|secs| secs * 1_000_000_000 + u128::from(item.mtime_nsec.unwrap_or(0))).unwrap_or(0);
+    format!("\"{:x}-{:x}\"", item.size, mtime)
+}
+
+// The segments recorded for another version of the file don't count
+fn segments_version(item: &FileEntry) -> String {
+    format!("{} {} {}", item.size, item.mtime.unwrap_or(0), item.mtime_nsec.unwrap_or(0))
+}
+
+// Ranges of the segments already written into the partial file, one "start-end" line each after the version line
+fn read_done_segments(segments_path: &Path, version: &str) -> HashSet<(u64, u64)> {
+    let Ok(contents) = std::fs::read_to_string(segments_path) else {
+        return HashSet::new();
+    };
+    let mut lines = contents.lines();
+    if lines.next() != Some(version) {
+        return HashSet::new();
     }
+    lines.filter_map(|line| {
+        let (start, end) = line.split_once('-')?;
+        Some((start.parse().ok()?, end.parse().ok()?))
+    }).collect()
+}
+
+// Called under the lock of the file's progress, so that the lines don't get mixed up
+fn record_done_segment(segments_path: &Path, segment: &Segment) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().append(true).open(segments_path)?;
+    file.write_all(format!("{}-{}\n", segment.start, segment.end).as_bytes())
 }
 
+// The entries are joined onto the destination mount points, so they can't be absolute nor go up
+fn check_relpath(relpath: &Path) -> Result<()> {
+    ensure!(relpath.components().all(|component| matches!(component, Component::Normal(_))), "Refusing an unsafe path: {}", relpath.display());
+    Ok(())
+}
+
+// Nothing is written through a symlink, be it sent by the server or a directory of another mount point
+fn check_ancestors(relpath: &Path, dst_path: &Path) -> Result<()> {
+    let depth = relpath.components().count();
+    for ancestor in dst_path.ancestors().skip(1).take(depth.saturating_sub(1)) {
+        let is_symlink = std::fs::symlink_metadata(ancestor).is_ok_and(|m| m.is_symlink());
+        ensure!(!is_symlink, "Refusing to write through a symlink: {}", ancestor.display());
+    }
+    Ok(())
+}
+
+fn check_busy(response: &http::Response<ureq::Body>) -> Result<()> {
+    if response.status() == 429 {
+        let retry_after = response.headers().get("Retry-After").and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok()).unwrap_or(1);
+        return Err(ServerBusy(Duration::from_secs(retry_after)).into());
+    }
+    Ok(())
+}
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+// Exponential backoff with jitter, so that the workers which failed together don't retry in lockstep
+fn backoff_delay(base: Duration, attempt: u32) -> Duration {
+    let delay = base.saturating_mul(2u32.saturating_pow(attempt)).min(MAX_RETRY_DELAY);
+    delay.mul_f64(rand::rng().random_range(0.5..1.5))
+}
+
+// None when no disk has room left
 fn roll_weighed_dice<'a>(input: &'a Vec<(&'a String, u64)>) -> Option<&'a str> {
     let total_space: u64 = input.iter().map(|(_, space)| space).sum();
+    if total_space == 0 {
+        return None;
+    }
     let mut rng = rand::rng();
     let mut choice = rng.random_range(0..total_space);
     for (mount, space) in input {
@@ -235,3 +1240,254 @@ fn roll_weighed_dice<'a>(input: &'a Vec<(&'a String, u64)>) -> Option<&'a str> {
     }
     Some(input.first()?.0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_delay() {
+        let base = Duration::from_secs(1);
+        for attempt in 0..5 {
+            let delay = backoff_delay(base, attempt);
+            let expected = base * 2u32.pow(attempt);
+            assert!(delay >= expected / 2 && delay <= expected * 3 / 2, "attempt {attempt}: {delay:?}");
+        }
+        // Capped, however many attempts and whatever the base
+        for (base, attempt) in [(base, 20), (base, u32::MAX), (Duration::MAX, 1)] {
+            assert!(backoff_delay(base, attempt) <= MAX_RETRY_DELAY * 3 / 2);
+        }
+        assert_eq!(backoff_delay(Duration::ZERO, 3), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_requeue_failed() {
+        let file = |relpath: &str| FileEntry { relpath: PathBuf::from(relpath), size: 1, ..Default::default() };
+        let mut state = SharedState { files_seen: 3, downloaded: 1, errors: 2, ..Default::default() };
+        state.failed = vec![(file("a.bin"), String::from("timeout")), (file("b.bin"), String::from("timeout"))];
+        assert_eq!(state.requeue_failed(), 2);
+        // They are counted again by the final pass
+        assert_eq!((state.files_seen, state.errors, state.downloaded), (1, 0, 1));
+        assert!(state.failed.is_empty());
+        assert_eq!(state.queue.iter().map(|item| item.relpath.to_str().unwrap()).collect::<Vec<_>>(), vec!["a.bin", "b.bin"]);
+
+        // No point retrying without any room left
+        let mut state = SharedState { files_seen: 1, errors: 1, out_of_space: true, ..Default::default() };
+        state.failed = vec![(file("c.bin"), String::from("no space"))];
+        assert_eq!(state.requeue_failed(), 0);
+        assert_eq!((state.files_seen, state.errors, state.failed.len()), (1, 1, 1));
+    }
+
+    fn worker(compare: CompareMode, endpoint: &str) -> Worker {
+        let settings = WorkerSettings {
+            endpoint: endpoint.to_owned(),
+            auth: String::from("token"),
+            dst_paths: vec![],
+            dry_run: false,
+            group_by: None,
+            retries: 0,
+            retry_delay: Duration::ZERO,
+            segment_threshold: 0,
+            segment_size: 1 << 20,
+            bwlimit: None,
+            progress: Arc::new(Progress::new(&[])),
+            ownership: Ownership::new(&[], &[]),
+            accept_encoding: "identity",
+            compare,
+            index_preload: HashMap::new(),
+        };
+        Worker::new(Arc::default(), Arc::default(), settings)
+    }
+
+    // Answers a single request with body, returns the endpoint
+    fn serve_once(content_type: &'static str, body: String) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _addr) = listener.accept().unwrap();
+            let mut request = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while request.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", content_type, body.len(), body).unwrap();
+        });
+        endpoint
+    }
+
+    // Answers a single download request with contents, or with the part of it asked for by a "bytes=N-" range
+    fn serve_file_once(contents: &'static [u8]) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _addr) = listener.accept().unwrap();
+            let mut request = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            let mut offset = None;
+            while request.read_line(&mut line).unwrap() > 2 {
+                offset = offset.or_else(|| line.strip_prefix("Range: bytes=")?.trim_end().strip_suffix('-')?.parse::<usize>().ok());
+                line.clear();
+            }
+            let (status, range) = match offset {
+                Some(offset) => ("206 Partial Content", format!("Content-Range: bytes {}-{}/{}\r\n", offset, contents.len() - 1, contents.len())),
+                None => ("200 OK", String::new()),
+            };
+            let body = &contents[offset.unwrap_or(0)..];
+            write!(stream, "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n", status, range, body.len()).unwrap();
+            stream.write_all(body).unwrap();
+        });
+        endpoint
+    }
+
+    #[test]
+    fn test_download_over_older_copy() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dst_path = tempdir.path().join("f.bin");
+        let item = FileEntry { relpath: PathBuf::from("f.bin"), size: 16, ..Default::default() };
+        let download = || {
+            let endpoint = serve_file_once(b"BBBBBBBBBBBBBBBB");
+            worker(CompareMode::Size, &endpoint).download(&format!("{endpoint}/download/f.bin"), &dst_path, &item)
+        };
+
+        // A shorter file under the real name is an older version, not the beginning of this one
+        std::fs::write(&dst_path, b"AAAAAAAA").unwrap();
+        assert!(matches!(download().unwrap(), DlStatus::Completed));
+        assert_eq!(std::fs::read(&dst_path).unwrap(), b"BBBBBBBBBBBBBBBB");
+        assert!(!partial_path(&dst_path).exists());
+
+        // Whereas an interrupted transfer is resumed, the older copy stays until it's complete
+        std::fs::write(&dst_path, b"AAAAAAAA").unwrap();
+        std::fs::write(partial_path(&dst_path), b"BBBBBBBB").unwrap();
+        assert!(matches!(download().unwrap(), DlStatus::Completed));
+        assert_eq!(std::fs::read(&dst_path).unwrap(), b"BBBBBBBBBBBBBBBB");
+        assert!(!partial_path(&dst_path).exists());
+    }
+
+    #[test]
+    fn test_is_up_to_date() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("file.bin");
+        std::fs::write(&path, b"oneone").unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        let item = FileEntry::from_metadata(PathBuf::from("file.bin"), &metadata);
+        let older = FileEntry { mtime: Some(1000), ..item.clone() };
+        let other_nsec = FileEntry { mtime_nsec: item.mtime_nsec.map(|nsec| (nsec + 1) % 1_000_000_000), ..item.clone() };
+        let bigger = FileEntry { size: 7, ..item.clone() };
+        let missing = tempdir.path().join("missing.bin");
+
+        let size = worker(CompareMode::Size, "http://127.0.0.1:9");
+        assert!(size.is_up_to_date(&item, &path).unwrap());
+        assert!(size.is_up_to_date(&older, &path).unwrap());
+        assert!(!size.is_up_to_date(&bigger, &path).unwrap());
+        assert!(!size.is_up_to_date(&item, &missing).unwrap());
+
+        let mtime = worker(CompareMode::Mtime, "http://127.0.0.1:9");
+        assert!(mtime.is_up_to_date(&item, &path).unwrap());
+        assert!(!mtime.is_up_to_date(&older, &path).unwrap());
+        assert!(!mtime.is_up_to_date(&other_nsec, &path).unwrap());
+        // Older servers don't send the nanoseconds
+        assert!(mtime.is_up_to_date(&FileEntry { mtime_nsec: None, ..item.clone() }, &path).unwrap());
+        assert!(!mtime.is_up_to_date(&bigger, &path).unwrap());
+
+        // The checksum is only asked for when the sizes match, whatever the mtime
+        let checksum = hash_file(&path).unwrap();
+        assert!(worker(CompareMode::Checksum, &serve_once("text/plain", checksum.clone())).is_up_to_date(&older, &path).unwrap());
+        assert!(!worker(CompareMode::Checksum, &serve_once("text/plain", String::from("0123456789abcdef"))).is_up_to_date(&item, &path).unwrap());
+        assert!(!worker(CompareMode::Checksum, "http://127.0.0.1:9").is_up_to_date(&bigger, &path).unwrap());
+        assert!(worker(CompareMode::Checksum, "http://127.0.0.1:9").is_up_to_date(&item, &path).is_err());
+    }
+
+    #[test]
+    fn test_fetch_list() {
+        let entries = "{\"relpath\":\"a.bin\",\"size\":3}\n{\"relpath\":\"b.bin\",\"size\":5}\n";
+        let fetch = |body: String| {
+            let mut relpaths = vec![];
+            let summary = fetch_list(&serve_once(NDJSON, body), "token", false, None, &Filter::default(), |item| {
+                relpaths.push(item.relpath);
+                Ok(())
+            });
+            (summary, relpaths)
+        };
+
+        let (summary, relpaths) = fetch(format!("{entries}{{\"end\":{{\"filtered\":2,\"warnings\":[{{\"path\":\"locked\",\"error\":\"Permission denied\"}}]}}}}\n"));
+        let summary = summary.unwrap();
+        assert_eq!(relpaths, vec![PathBuf::from("a.bin"), PathBuf::from("b.bin")]);
+        assert_eq!(summary.filtered, 2);
+        assert_eq!(summary.warnings, vec![WalkError::new(Path::new("locked"), "Permission denied")]);
+
+        // The server has stopped in the middle of the list
+        let (summary, relpaths) = fetch(entries.to_owned());
+        assert!(summary.unwrap_err().to_string().contains("incomplete"));
+        assert_eq!(relpaths.len(), 2);
+    }
+
+    #[test]
+    fn test_roll_weighed_dice() {
+        let (disk1, disk2) = (String::from("/mnt/1"), String::from("/mnt/2"));
+        assert_eq!(roll_weighed_dice(&vec![]), None);
+        assert_eq!(roll_weighed_dice(&vec![(&disk1, 0), (&disk2, 0)]), None);
+        assert_eq!(roll_weighed_dice(&vec![(&disk1, 0), (&disk2, 10)]), Some("/mnt/2"));
+        for _ in 0..100 {
+            assert!(roll_weighed_dice(&vec![(&disk1, 5), (&disk2, 10)]).is_some());
+        }
+    }
+
+    #[test]
+    fn test_done_segments() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let segments_path = tempdir.path().join("file.bin.jbodncp.segments");
+        let item = FileEntry { relpath: PathBuf::from("file.bin"), size: 300, mtime: Some(1000), ..Default::default() };
+        let file = Arc::new(SegmentedFile {
+            item: item.clone(),
+            download_url: String::new(),
+            dst_path: tempdir.path().join("file.bin"),
+            tmp_path: tempdir.path().join("file.bin.jbodncp.partial"),
+            segments_path: segments_path.clone(),
+            progress: Mutex::new(SegmentProgress { remaining: 3, failed: false, checksum: None }),
+        });
+        std::fs::write(&segments_path, format!("{}\n", segments_version(&item))).unwrap();
+        for (start, end) in [(0, 99), (200, 299)] {
+            record_done_segment(&segments_path, &Segment { file: file.clone(), start, end }).unwrap();
+        }
+        assert_eq!(read_done_segments(&segments_path, &segments_version(&item)), [(0, 99), (200, 299)].into());
+
+        // The file has changed on the server since
+        let changed = FileEntry { mtime: Some(2000), ..item };
+        assert!(read_done_segments(&segments_path, &segments_version(&changed)).is_empty());
+        assert!(read_done_segments(&tempdir.path().join("missing"), &segments_version(&changed)).is_empty());
+    }
+
+    #[test]
+    fn test_file_etag() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("file.bin");
+        std::fs::write(&path, b"oneone").unwrap();
+        std::fs::File::options().write(true).open(&path).unwrap()
+            .set_modified(std::time::UNIX_EPOCH + std::time::Duration::new(1_700_000_000, 123_456_789)).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        let item = FileEntry::from_metadata(PathBuf::from("file.bin"), &metadata);
+        assert_eq!(file_etag(&item), crate::server::make_etag(&metadata));
+    }
+
+    #[test]
+    fn test_check_dst_path() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mount_point = tempdir.path().join("1");
+        std::fs::create_dir_all(mount_point.join("dir/subdir")).unwrap();
+        std::fs::create_dir_all(tempdir.path().join("etc")).unwrap();
+        std::os::unix::fs::symlink(tempdir.path().join("etc"), mount_point.join("a")).unwrap();
+        std::os::unix::fs::symlink("subdir", mount_point.join("dir/link")).unwrap();
+        let check = |relpath: &str| check_relpath(Path::new(relpath)).and_then(|()| check_ancestors(Path::new(relpath), &mount_point.join(relpath)));
+
+        assert!(check("dir/subdir/file.bin").is_ok());
+        assert!(check("new/file.bin").is_ok());
+        // The symlink itself can be replaced
+        assert!(check("a").is_ok());
+        assert!(check("a/passwd").is_err());
+        assert!(check("dir/link/file.bin").is_err());
+        assert!(check("../etc/passwd").is_err());
+        assert!(check("dir/../../etc/passwd").is_err());
+        assert!(check("/etc/passwd").is_err());
+        assert!(check("./file.bin").is_err());
+    }
+}
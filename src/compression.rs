@@ -0,0 +1,166 @@
+use std::io::{ self, Read, Write };
+use std::path::Path;
+use axum::body::Bytes;
+use futures_util::{ Stream, StreamExt };
+use http::HeaderValue;
+
+/// Accept-Encoding sent by the client when compression is enabled, in the order of preference
+pub const ACCEPT_COMPRESSED: &str = "zstd, gzip";
+
+// Already compressed formats, which aren't worth compressing again
+const INCOMPRESSIBLE_EXTENSIONS: &[&str] = &[
+    "gz", "tgz", "bz2", "xz", "zst", "lz4", "lzma", "zip", "7z", "rar",
+    "jpg", "jpeg", "png", "gif", "webp", "heic",
+    "mp3", "ogg", "flac", "aac", "mp4", "mkv", "avi", "mov", "webm",
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Encoding {
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Picks the encoding for a response, zstd being preferred over gzip
+pub fn negotiate(accept_encoding: Option<&HeaderValue>) -> Option<Encoding> {
+    let accepted: Vec<&str> = accept_encoding?.to_str().ok()?.split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let name = params.next()?.trim();
+            let refused = params.any(|param| param.trim().strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0));
+            (!refused).then_some(name)
+        })
+        .collect();
+    [Encoding::Zstd, Encoding::Gzip].into_iter().find(|encoding| accepted.iter().any(|name| *name == encoding.name() || *name == "*"))
+}
+
+pub fn is_compressible(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .is_none_or(|ext| !INCOMPRESSIBLE_EXTENSIONS.contains(&ext.as_str()))
+}
+
+pub enum Encoder {
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    pub fn new(encoding: Encoding) -> io::Result<Encoder> {
+        Ok(match encoding {
+            Encoding::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(vec![], 0)?),
+            Encoding::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(vec![], flate2::Compression::default())),
+        })
+    }
+    /// Returns the compressed output which is ready so far
+    pub fn compress(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(match self {
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(chunk)?;
+                std::mem::take(encoder.get_mut())
+            }
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                std::mem::take(encoder.get_mut())
+            }
+        })
+    }
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Gzip(encoder) => encoder.finish(),
+        }
+    }
+}
+
+pub fn compress(data: &[u8], encoding: Encoding) -> io::Result<Vec<u8>> {
+    let mut encoder = Encoder::new(encoding)?;
+    let mut compressed = encoder.compress(data)?;
+    compressed.extend(encoder.finish()?);
+    Ok(compressed)
+}
+
+/// Compresses a response body on the fly
+pub fn encode_stream<S>(stream: S, encoder: Encoder) -> impl Stream<Item = io::Result<Bytes>>
+where
+    S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+{
+    futures_util::stream::unfold((Box::pin(stream), Some(encoder)), |(mut stream, encoder)| async move {
+        let mut encoder = encoder?;
+        loop {
+            match stream.next().await {
+                Some(Ok(chunk)) => match encoder.compress(&chunk) {
+                    Ok(compressed) if compressed.is_empty() => continue,
+                    Ok(compressed) => return Some((Ok(Bytes::from(compressed)), (stream, Some(encoder)))),
+                    Err(err) => return Some((Err(err), (stream, None))),
+                },
+                Some(Err(err)) => return Some((Err(err), (stream, None))),
+                None => return Some((encoder.finish().map(Bytes::from), (stream, None))),
+            }
+        }
+    })
+}
+
+/// Undoes the Content-Encoding of a response
+pub fn decoder<'a, R: Read + 'a>(reader: R, content_encoding: Option<&str>) -> io::Result<Box<dyn Read + 'a>> {
+    match content_encoding.map(str::trim) {
+        None | Some("identity") => Ok(Box::new(reader)),
+        Some("zstd") => Ok(Box::new(zstd::stream::read::Decoder::new(reader)?)),
+        Some("gzip") => Ok(Box::new(flate2::read::MultiGzDecoder::new(reader))),
+        Some(other) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported Content-Encoding: {other}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn negotiate_str(value: &str) -> Option<Encoding> {
+        negotiate(Some(&HeaderValue::from_str(value).unwrap()))
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(None), None);
+        assert_eq!(negotiate_str("identity"), None);
+        assert_eq!(negotiate_str("gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate_str("gzip, zstd"), Some(Encoding::Zstd));
+        assert_eq!(negotiate_str("zstd;q=0, gzip;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate_str("*"), Some(Encoding::Zstd));
+        assert_eq!(negotiate_str("br"), None);
+    }
+
+    #[test]
+    fn test_is_compressible() {
+        assert!(is_compressible(Path::new("logs/app.log")));
+        assert!(is_compressible(Path::new("README")));
+        assert!(!is_compressible(Path::new("backup/db.tar.GZ")));
+        assert!(!is_compressible(Path::new("video.mkv")));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let data = b"2026-10-17 INFO Everything is done\n".repeat(1000);
+        for encoding in [Encoding::Zstd, Encoding::Gzip] {
+            let mut encoder = Encoder::new(encoding).unwrap();
+            let mut compressed = vec![];
+            for chunk in data.chunks(4096) {
+                compressed.extend(encoder.compress(chunk).unwrap());
+            }
+            compressed.extend(encoder.finish().unwrap());
+            assert!(compressed.len() < data.len() / 10);
+
+            let mut decompressed = vec![];
+            decoder(&compressed[..], Some(encoding.name())).unwrap().read_to_end(&mut decompressed).unwrap();
+            assert_eq!(decompressed, data);
+        }
+    }
+}
@@ -1,4 +1,7 @@
 use libc::{statvfs, c_char};
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
 use std::ffi::CString;
 use std::mem::MaybeUninit;
 use std::path::Path;
@@ -10,9 +13,69 @@ pub fn get_available_space(path: &Path) -> Option<u64> {
     unsafe {
         if statvfs(c_path.as_ptr() as *const c_char, stat.as_mut_ptr()) == 0 {
             let stat = stat.assume_init();
+            // statvfs field widths differ between platforms
+            #[allow(clippy::unnecessary_cast)]
             Some(stat.f_bsize as u64 * stat.f_bavail as u64)
         } else {
             None
         }
     }
 }
+
+/// Sets the file length and reserves its blocks upfront where the filesystem supports it
+pub fn preallocate(file: &File, size: u64) -> io::Result<()> {
+    file.set_len(size)?;
+    let ret = unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, size as libc::off_t) };
+    match ret {
+        0 | libc::EOPNOTSUPP | libc::EINVAL => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+/// Lists the (offset, length) extents of the file which hold data, the rest of it being holes
+pub fn data_extents(file: &File) -> io::Result<Vec<(u64, u64)>> {
+    let size = file.metadata()?.len() as libc::off_t;
+    let fd = file.as_raw_fd();
+    let mut extents = vec![];
+    let mut pos: libc::off_t = 0;
+    while pos < size {
+        let start = unsafe { libc::lseek(fd, pos, libc::SEEK_DATA) };
+        if start < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // No data past pos
+                Some(libc::ENXIO) => break,
+                // Holes aren't supported by the filesystem, so it's all data
+                Some(libc::EINVAL) => return Ok(vec![(0, size as u64)]),
+                _ => return Err(err),
+            }
+        }
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        extents.push((start as u64, (end - start) as u64));
+        pos = end;
+    }
+    Ok(extents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+
+    #[test]
+    fn test_data_extents() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = File::create(dir.path().join("sparse")).unwrap();
+        file.set_len(8 << 20).unwrap();
+        assert_eq!(data_extents(&file).unwrap(), vec![]);
+
+        file.write_all_at(b"data", 4 << 20).unwrap();
+        let extents = data_extents(&file).unwrap();
+        assert_eq!(extents.len(), 1);
+        let (offset, len) = extents[0];
+        assert!(offset <= 4 << 20 && offset + len >= (4 << 20) + 4 && offset + len < 8 << 20, "{extents:?}");
+    }
+}
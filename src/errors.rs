@@ -0,0 +1,65 @@
+use std::fmt;
+
+/// Failures which are reported with their own process exit codes
+#[derive(Debug)]
+pub enum Failure {
+    /// Some files couldn't be transferred
+    Partial,
+    /// No destination disk has enough space left
+    OutOfSpace,
+    /// The server has rejected the token
+    AuthFailed,
+    /// --delete would remove more files than allowed by --max-delete-percent
+    TooManyDeletions,
+    /// verify has found differences between the server and the destination
+    Mismatch,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Partial => write!(f, "Some files weren't transferred"),
+            Failure::OutOfSpace => write!(f, "No available disks left"),
+            Failure::AuthFailed => write!(f, "Authorization failed, check the --auth token"),
+            Failure::TooManyDeletions => write!(f, "Too many files would be deleted, check --max-delete-percent"),
+            Failure::Mismatch => write!(f, "The destination doesn't match the server"),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+pub const EXIT_FATAL: u8 = 1;
+pub const EXIT_PARTIAL: u8 = 2;
+pub const EXIT_OUT_OF_SPACE: u8 = 3;
+pub const EXIT_AUTH_FAILED: u8 = 4;
+pub const EXIT_TOO_MANY_DELETIONS: u8 = 5;
+pub const EXIT_MISMATCH: u8 = 6;
+
+pub fn exit_code(err: &anyhow::Error) -> u8 {
+    match err.downcast_ref::<Failure>() {
+        Some(Failure::Partial) => EXIT_PARTIAL,
+        Some(Failure::OutOfSpace) => EXIT_OUT_OF_SPACE,
+        Some(Failure::AuthFailed) => EXIT_AUTH_FAILED,
+        Some(Failure::TooManyDeletions) => EXIT_TOO_MANY_DELETIONS,
+        Some(Failure::Mismatch) => EXIT_MISMATCH,
+        None => EXIT_FATAL,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(&Failure::Partial.into()), EXIT_PARTIAL);
+        assert_eq!(exit_code(&Failure::OutOfSpace.into()), EXIT_OUT_OF_SPACE);
+        assert_eq!(exit_code(&Failure::AuthFailed.into()), EXIT_AUTH_FAILED);
+        assert_eq!(exit_code(&Failure::TooManyDeletions.into()), EXIT_TOO_MANY_DELETIONS);
+        assert_eq!(exit_code(&Failure::Mismatch.into()), EXIT_MISMATCH);
+        // Still found under some context
+        assert_eq!(exit_code(&anyhow::Error::from(Failure::OutOfSpace).context("Download failed")), EXIT_OUT_OF_SPACE);
+        assert_eq!(exit_code(&anyhow::anyhow!("Couldn't fetch the file list")), EXIT_FATAL);
+    }
+}
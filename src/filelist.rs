@@ -1,35 +1,433 @@
 use std::fs;
 use std::io;
+use std::os::unix::fs::{ MetadataExt, FileTypeExt };
 use std::path::{Path, PathBuf};
-use std::collections::VecDeque;
+use std::collections::{ VecDeque, HashMap, HashSet };
+use std::sync::{ Mutex, Condvar };
 use serde::{Serialize, Deserialize};
+use log::*;
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    #[default]
+    File,
+    Dir,
+    Symlink,
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+}
+
+impl EntryKind {
+    pub fn is_file(&self) -> bool {
+        *self == EntryKind::File
+    }
+    fn from_file_type(file_type: fs::FileType) -> EntryKind {
+        if file_type.is_dir() {
+            EntryKind::Dir
+        } else if file_type.is_symlink() {
+            EntryKind::Symlink
+        } else if file_type.is_fifo() {
+            EntryKind::Fifo
+        } else if file_type.is_socket() {
+            EntryKind::Socket
+        } else if file_type.is_block_device() {
+            EntryKind::BlockDevice
+        } else if file_type.is_char_device() {
+            EntryKind::CharDevice
+        } else {
+            EntryKind::File
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
 pub struct FileEntry {
     pub relpath: PathBuf,
     pub size: u64,
+    // entry type, only directories without any entries in them are listed
+    #[serde(default, skip_serializing_if = "EntryKind::is_file")]
+    pub kind: EntryKind,
+    // symlink target
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+    // this file is a hard link to another entry of the list (the first one with the same inode)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<PathBuf>,
+    // other entries of the list may be hard links to this one
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub linked: bool,
+    // the file has holes, its data extents are listed by /extents
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sparse: bool,
+    // metadata, optional for compatibility with older servers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime_nsec: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+}
+
+impl FileEntry {
+    pub fn from_metadata(relpath: PathBuf, metadata: &fs::Metadata) -> FileEntry {
+        let kind = EntryKind::from_file_type(metadata.file_type());
+        FileEntry {
+            relpath,
+            size: if kind.is_file() { metadata.len() } else { 0 },
+            kind,
+            target: None,
+            link: None,
+            linked: false,
+            // fewer blocks allocated than the size needs
+            sparse: kind.is_file() && metadata.blocks() * 512 < metadata.len(),
+            mtime: Some(metadata.mtime()),
+            mtime_nsec: Some(metadata.mtime_nsec() as u32),
+            mode: Some(metadata.mode() & 0o7777),
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+        }
+    }
+}
+
+/// Suffix of the files being downloaded; they are renamed into place once complete
+pub const PARTIAL_SUFFIX: &str = ".jbodncp.partial";
+
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(PARTIAL_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Segments already downloaded into the partial file of a segmented download
+pub const SEGMENTS_SUFFIX: &str = ".jbodncp.segments";
+
+pub fn segments_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(SEGMENTS_SUFFIX);
+    path.with_file_name(name)
 }
 
-pub fn list_files_bfs(base: &Path) -> io::Result<Vec<FileEntry>> {
+// The records of the segmented downloads are left out along with the partial files
+pub fn is_partial(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| {
+        let name = name.to_string_lossy();
+        name.ends_with(PARTIAL_SUFFIX) || name.ends_with(SEGMENTS_SUFFIX)
+    })
+}
+
+/// Content type of the streamed /list: one ListLine per line
+pub const NDJSON: &str = "application/x-ndjson";
+
+/// A line of the streamed /list, the entries are followed by a summary
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum ListLine {
+    End { end: ListSummary },
+    Entry(FileEntry),
+}
+
+/// Last line of the streamed /list, a list without it is incomplete
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ListSummary {
+    // entries left out by the filters of the request
+    pub filtered: usize,
+    // what the server couldn't list, the list lacks whatever is below these paths
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<WalkError>,
+}
+
+/// A directory or an entry which couldn't be read, the walk goes on without it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WalkError {
+    // relative to the base of the walk
+    pub path: PathBuf,
+    pub error: String,
+}
+
+impl WalkError {
+    pub fn new(path: &Path, error: impl std::fmt::Display) -> WalkError {
+        WalkError { path: path.to_path_buf(), error: error.to_string() }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WalkOptions {
+    // threads reading the directories of each base
+    pub threads: usize,
+    // don't walk into the directories of another file system
+    pub one_file_system: bool,
+}
+
+// Walks the subtree at prefix only, relpaths are still relative to base
+pub fn list_files_bfs(base: &Path, prefix: &Path) -> io::Result<Vec<FileEntry>> {
     let mut results = Vec::new();
-    let mut queue = VecDeque::new();
+    let warnings = walk_bfs(base, prefix, &WalkOptions::default(), |_item| true, |item| {
+        results.push(item);
+        Ok(())
+    })?;
+    for warning in warnings {
+        warn!("Couldn't list {}: {}", base.join(&warning.path).display(), warning.error);
+    }
+    Ok(results)
+}
 
-    let base = fs::canonicalize(base)?;
-    queue.push_back(base.clone());
+/// Same as list_files_bfs with several threads, the entries are handed over to visit as soon as they are found.
+/// The entries for which keep returns false are left out before the hard links are assigned,
+/// so that a hard link and its target are always kept together.
+/// Only an error returned by visit stops the walk, what can't be read is returned as warnings
+pub fn walk_bfs(
+    base: &Path,
+    prefix: &Path,
+    options: &WalkOptions,
+    keep: impl Fn(&FileEntry) -> bool + Sync,
+    visit: impl FnMut(FileEntry) -> io::Result<()> + Send,
+) -> io::Result<Vec<WalkError>> {
+    let base = match fs::canonicalize(base) {
+        Ok(base) => base,
+        Err(err) => return Ok(vec![WalkError::new(Path::new(""), err)]),
+    };
+    // Hard links are assigned in the order the entries are handed over, so that a hard link never comes before its target
+    let sink = Mutex::new((HardLinks::default(), visit));
+    walk_tree(&base, prefix, options, |relpath, metadata, warnings| {
+        let mut contents = read_dir_contents(&base, &base.join(relpath));
+        warnings.append(&mut contents.errors);
+        let mut entries: Vec<_> = contents.entries.into_iter().filter(|(item, _inode)| keep(item)).collect();
+        // Empty directories are listed so that they get created on the other end
+        if contents.is_empty && !relpath.as_os_str().is_empty() {
+            let item = FileEntry::from_metadata(relpath.to_path_buf(), metadata);
+            if keep(&item) {
+                entries.push((item, None));
+            }
+        }
 
-    while let Some(current_dir) = queue.pop_front() {
-        for entry in fs::read_dir(&current_dir)? {
-            let path = entry?.path();
+        let mut sink = sink.lock().unwrap();
+        let (hard_links, visit) = &mut *sink;
+        for (mut item, inode) in entries {
+            hard_links.assign(&mut item, inode);
+            visit(item)?;
+        }
+        Ok(contents.subdirs)
+    })
+}
 
-            if path.is_dir() {
-                queue.push_back(path);
-            } else if path.is_file() {
-                let size = fs::metadata(&path)?.len();
-                let relpath = path.strip_prefix(&base).unwrap_or(&path).to_path_buf();
-                results.push(FileEntry { relpath, size });
+/// Calls visit_dir once for every directory of the subtree at base/prefix, with its relpath and metadata.
+/// visit_dir returns the subdirectories to be walked next, they are shared among the threads.
+/// A directory is walked once even if it can be reached twice (e.g. through a bind mount), so that loops end
+pub fn walk_tree(
+    base: &Path,
+    prefix: &Path,
+    options: &WalkOptions,
+    visit_dir: impl Fn(&Path, &fs::Metadata, &mut Vec<WalkError>) -> io::Result<Vec<PathBuf>> + Sync,
+) -> io::Result<Vec<WalkError>> {
+    let start = base.join(prefix);
+    let Ok(metadata) = fs::symlink_metadata(&start) else {
+        return Ok(vec![]);
+    };
+    // Symlinks aren't followed, not even in the prefix
+    if !metadata.is_dir() || fs::canonicalize(&start).ok().as_deref() != Some(start.as_path()) {
+        return Ok(vec![]);
+    }
+    let pool = DirPool {
+        base,
+        // The one of base, a prefix on another file system is left out as well
+        device: options.one_file_system.then(|| fs::symlink_metadata(base).map(|m| m.dev()).unwrap_or(metadata.dev())),
+        state: Mutex::new(DirPoolState { queue: VecDeque::from([prefix.to_path_buf()]), ..Default::default() }),
+        wakeup: Condvar::new(),
+    };
+    std::thread::scope(|scope| {
+        for _ in 1..options.threads {
+            scope.spawn(|| pool.work(&visit_dir));
+        }
+        pool.work(&visit_dir);
+    });
+    let state = pool.state.into_inner().unwrap();
+    match state.error {
+        Some(err) => Err(err),
+        None => Ok(state.warnings),
+    }
+}
+
+struct DirPool<'a> {
+    base: &'a Path,
+    // the device of the walked file system, with --one-file-system
+    device: Option<u64>,
+    state: Mutex<DirPoolState>,
+    wakeup: Condvar,
+}
+
+#[derive(Default)]
+struct DirPoolState {
+    queue: VecDeque<PathBuf>,
+    // directories being read, their subdirectories are yet to come
+    busy: usize,
+    // (dev, ino) of the directories walked so far
+    walked: HashSet<(u64, u64)>,
+    warnings: Vec<WalkError>,
+    // returned by visit_dir, it stops every thread
+    error: Option<io::Error>,
+}
+
+impl DirPool<'_> {
+    fn work(&self, visit_dir: &impl Fn(&Path, &fs::Metadata, &mut Vec<WalkError>) -> io::Result<Vec<PathBuf>>) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.error.is_some() {
+                return;
+            }
+            if let Some(relpath) = state.queue.pop_front() {
+                state.busy += 1;
+                drop(state);
+                let mut warnings = vec![];
+                let result = self.visit(&relpath, visit_dir, &mut warnings);
+                state = self.state.lock().unwrap();
+                state.busy -= 1;
+                state.warnings.append(&mut warnings);
+                match result {
+                    Ok(subdirs) => state.queue.extend(subdirs),
+                    Err(err) => state.error = Some(err),
+                }
+                self.wakeup.notify_all();
+                continue;
+            }
+            if state.busy == 0 {
+                return;
             }
+            state = self.wakeup.wait(state).unwrap();
         }
     }
+    fn visit(&self, relpath: &Path, visit_dir: &impl Fn(&Path, &fs::Metadata, &mut Vec<WalkError>) -> io::Result<Vec<PathBuf>>, warnings: &mut Vec<WalkError>) -> io::Result<Vec<PathBuf>> {
+        let metadata = match fs::symlink_metadata(self.base.join(relpath)) {
+            Ok(metadata) => metadata,
+            // Removed in the meantime
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => {
+                warnings.push(WalkError::new(relpath, err));
+                return Ok(vec![]);
+            }
+        };
+        if self.device.is_some_and(|device| device != metadata.dev()) {
+            debug!("Not crossing into another file system: {}", self.base.join(relpath).display());
+            return Ok(vec![]);
+        }
+        if !self.state.lock().unwrap().walked.insert((metadata.dev(), metadata.ino())) {
+            warnings.push(WalkError::new(relpath, "already walked through another path, skipped to avoid a loop"));
+            return Ok(vec![]);
+        }
+        visit_dir(relpath, &metadata, warnings)
+    }
+}
 
-    Ok(results)
+/// What a directory holds, relpaths are relative to the base of the walk
+#[derive(Default)]
+pub struct DirContents {
+    // everything but the subdirectories, sorted by relpath,
+    // along with the inode of the files which have other hard links
+    pub entries: Vec<(FileEntry, Option<(u64, u64)>)>,
+    pub subdirs: Vec<PathBuf>,
+    // partial files count too, an unreadable directory isn't empty
+    pub is_empty: bool,
+    // the directory itself or the entries which couldn't be read, they are left out
+    pub errors: Vec<WalkError>,
+}
+
+pub fn read_dir_contents(base: &Path, dir: &Path) -> DirContents {
+    let mut contents = DirContents::default();
+    let relpath = |path: &Path| path.strip_prefix(base).unwrap_or(path).to_path_buf();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            contents.errors.push(WalkError::new(&relpath(dir), err));
+            return contents;
+        }
+    };
+    contents.is_empty = true;
+    for entry in entries {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(err) => {
+                contents.errors.push(WalkError::new(&relpath(dir), err));
+                continue;
+            }
+        };
+        contents.is_empty = false;
+        if is_partial(&path) {
+            continue;
+        }
+        match read_entry(&path, relpath(&path), &mut contents) {
+            Ok(()) => {},
+            // Removed in the meantime
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => contents.errors.push(WalkError::new(&relpath(&path), err)),
+        }
+    }
+    contents.entries.sort_by(|(a, _), (b, _)| a.relpath.cmp(&b.relpath));
+    contents.subdirs.sort();
+    contents
+}
+
+// Symlinks aren't followed, they are listed as they are
+fn read_entry(path: &Path, relpath: PathBuf, contents: &mut DirContents) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        contents.subdirs.push(relpath);
+        return Ok(());
+    }
+    let mut item = FileEntry::from_metadata(relpath, &metadata);
+    if item.kind == EntryKind::Symlink {
+        item.target = Some(fs::read_link(path)?);
+    }
+    let inode = (item.kind.is_file() && metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()));
+    contents.entries.push((item, inode));
+    Ok(())
+}
+
+/// Makes the files sharing an inode hard links to the first one of them found by the walk
+#[derive(Default)]
+pub struct HardLinks(HashMap<(u64, u64), PathBuf>);
+
+impl HardLinks {
+    pub fn assign(&mut self, item: &mut FileEntry, inode: Option<(u64, u64)>) {
+        let Some(inode) = inode else {
+            return;
+        };
+        let leader = self.0.entry(inode).or_insert_with(|| item.relpath.clone());
+        if *leader != item.relpath {
+            item.link = Some(leader.clone());
+        } else {
+            item.linked = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_line() {
+        let line: ListLine = serde_json::from_str(r#"{"relpath":"dir/file.bin","size":3,"mtime":1000}"#).unwrap();
+        assert!(matches!(line, ListLine::Entry(FileEntry { ref relpath, size: 3, mtime: Some(1000), .. }) if relpath == Path::new("dir/file.bin")));
+        // Even a file named "end" is an entry
+        let line: ListLine = serde_json::from_str(r#"{"relpath":"end","size":0}"#).unwrap();
+        assert!(matches!(line, ListLine::Entry(FileEntry { ref relpath, .. }) if relpath == Path::new("end")));
+
+        let line: ListLine = serde_json::from_str(r#"{"end":{"filtered":2,"warnings":[{"path":"locked","error":"Permission denied"}]}}"#).unwrap();
+        let ListLine::End { end } = line else {
+            panic!("not an end line: {:?}", line);
+        };
+        assert_eq!(end.filtered, 2);
+        assert_eq!(end.warnings, vec![WalkError::new(Path::new("locked"), "Permission denied")]);
+        // Written the same way it's read
+        let line = serde_json::to_string(&ListLine::End { end: ListSummary { filtered: 1, warnings: vec![] } }).unwrap();
+        assert!(matches!(serde_json::from_str(&line).unwrap(), ListLine::End { end: ListSummary { filtered: 1, .. } }));
+
+        assert!(serde_json::from_str::<ListLine>(r#"{"size":3}"#).is_err());
+    }
 }
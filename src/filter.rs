@@ -0,0 +1,164 @@
+use std::path::Path;
+use glob::{ Pattern, MatchOptions };
+use crate::filelist::{ FileEntry, EntryKind };
+
+/// /list response header with the number of entries left out by the filters of the request
+pub const FILTERED_HEADER: &str = "X-Jbodncp-Filtered";
+
+// '*' stops at slashes, '**' goes through them
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// An --include or --exclude pattern, matched the way rsync does:
+/// "/dir/*.log" is anchored to the top of the tree, "dir/*.log" may match at any depth,
+/// "*.log" matches the file name only and "cache/" matches directories only
+#[derive(Clone, Debug)]
+pub struct FilterRule {
+    include: bool,
+    source: String,
+    pattern: Pattern,
+    anchored: bool,
+    dir_only: bool,
+    whole_path: bool,
+}
+
+impl FilterRule {
+    pub fn new(include: bool, pattern: &str) -> Result<FilterRule, String> {
+        let anchored = pattern.starts_with('/');
+        let dir_only = pattern.ends_with('/');
+        let trimmed = pattern.trim_start_matches('/').trim_end_matches('/');
+        let whole_path = anchored || trimmed.contains('/') || trimmed.contains("**");
+        let compiled = Pattern::new(trimmed).map_err(|err| format!("invalid pattern {pattern}: {err}"))?;
+        Ok(FilterRule { include, source: pattern.to_string(), anchored, dir_only, whole_path, pattern: compiled })
+    }
+    pub fn include(pattern: &str) -> Result<FilterRule, String> {
+        FilterRule::new(true, pattern)
+    }
+    pub fn exclude(pattern: &str) -> Result<FilterRule, String> {
+        FilterRule::new(false, pattern)
+    }
+    /// The rule as a /list query parameter
+    pub fn query_param(&self) -> (&'static str, &str) {
+        (if self.include { "include" } else { "exclude" }, &self.source)
+    }
+    fn matches(&self, relpath: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if !self.whole_path {
+            return relpath.file_name().is_some_and(|name| self.pattern.matches_with(&name.to_string_lossy(), MATCH_OPTIONS));
+        }
+        let relpath = relpath.to_string_lossy();
+        if self.anchored {
+            return self.pattern.matches_with(&relpath, MATCH_OPTIONS);
+        }
+        // Unanchored patterns may start at any directory
+        std::iter::once(&relpath[..]).chain(relpath.match_indices('/').map(|(idx, _)| &relpath[idx + 1..]))
+            .any(|suffix| self.pattern.matches_with(suffix, MATCH_OPTIONS))
+    }
+}
+
+/// Decides which entries of the list get downloaded
+#[derive(Default)]
+pub struct Filter {
+    pub rules: Vec<FilterRule>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub newer_than: Option<i64>,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.min_size.is_none() && self.max_size.is_none() && self.newer_than.is_none()
+    }
+    pub fn matches(&self, item: &FileEntry) -> bool {
+        (!item.kind.is_file() || self.matches_size_and_mtime(item)) && self.matches_path(item)
+    }
+    /// The --include and --exclude rules only, which don't depend on the copy at hand
+    pub fn matches_path(&self, item: &FileEntry) -> bool {
+        // Like in rsync, an excluded directory takes everything below it along
+        let mut ancestors: Vec<&Path> = item.relpath.ancestors().skip(1).filter(|path| !path.as_os_str().is_empty()).collect();
+        ancestors.reverse();
+        ancestors.iter().all(|dir| self.is_included(dir, true)) && self.is_included(&item.relpath, item.kind == EntryKind::Dir)
+    }
+    /// Same rules, without the size and mtime filters
+    pub fn path_rules(&self) -> Filter {
+        Filter { rules: self.rules.clone(), ..Default::default() }
+    }
+    fn matches_size_and_mtime(&self, item: &FileEntry) -> bool {
+        self.min_size.is_none_or(|min_size| item.size >= min_size)
+            && self.max_size.is_none_or(|max_size| item.size <= max_size)
+            // Entries coming from older servers don't have any mtime, so they are kept
+            && self.newer_than.zip(item.mtime).is_none_or(|(newer_than, mtime)| mtime >= newer_than)
+    }
+    // The first matching rule wins, anything not matched is included
+    fn is_included(&self, relpath: &Path, is_dir: bool) -> bool {
+        self.rules.iter().find(|rule| rule.matches(relpath, is_dir)).is_none_or(|rule| rule.include)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn file(relpath: &str, size: u64, mtime: i64) -> FileEntry {
+        FileEntry { relpath: PathBuf::from(relpath), size, mtime: Some(mtime), ..Default::default() }
+    }
+
+    fn matching(filter: &Filter, items: &[FileEntry]) -> Vec<String> {
+        items.iter().filter(|item| filter.matches(item)).map(|item| item.relpath.display().to_string()).collect()
+    }
+
+    #[test]
+    fn test_rules() {
+        let items = [
+            file("a.log", 1, 0),
+            file("a.txt", 1, 0),
+            file("logs/b.log", 1, 0),
+            file("logs/old/c.log", 1, 0),
+            file("cache/d.log", 1, 0),
+            file("x/cache/e.txt", 1, 0),
+            FileEntry { relpath: PathBuf::from("empty"), kind: EntryKind::Dir, ..Default::default() },
+        ];
+
+        let filter = Filter { rules: vec![FilterRule::exclude("*.log").unwrap()], ..Default::default() };
+        assert_eq!(matching(&filter, &items), ["a.txt", "x/cache/e.txt", "empty"]);
+
+        // First match wins
+        let filter = Filter { rules: vec![FilterRule::include("a.*").unwrap(), FilterRule::exclude("*.txt").unwrap()], ..Default::default() };
+        assert_eq!(matching(&filter, &items), ["a.log", "a.txt", "logs/b.log", "logs/old/c.log", "cache/d.log", "empty"]);
+        let filter = Filter { rules: vec![FilterRule::exclude("*.txt").unwrap(), FilterRule::include("a.*").unwrap()], ..Default::default() };
+        assert_eq!(matching(&filter, &items), ["a.log", "logs/b.log", "logs/old/c.log", "cache/d.log", "empty"]);
+
+        // Nothing below an excluded directory can be included back
+        let filter = Filter { rules: vec![FilterRule::include("logs/old/*").unwrap(), FilterRule::exclude("logs/").unwrap()], ..Default::default() };
+        assert_eq!(matching(&filter, &items), ["a.log", "a.txt", "cache/d.log", "x/cache/e.txt", "empty"]);
+
+        // Anchored and unanchored directories
+        let filter = Filter { rules: vec![FilterRule::exclude("/cache/").unwrap()], ..Default::default() };
+        assert_eq!(matching(&filter, &items), ["a.log", "a.txt", "logs/b.log", "logs/old/c.log", "x/cache/e.txt", "empty"]);
+        let filter = Filter { rules: vec![FilterRule::exclude("cache/").unwrap()], ..Default::default() };
+        assert_eq!(matching(&filter, &items), ["a.log", "a.txt", "logs/b.log", "logs/old/c.log", "empty"]);
+
+        // Directories have to be included for the files below them to be reached
+        let filter = Filter { rules: vec![FilterRule::include("*/").unwrap(), FilterRule::include("*.log").unwrap(), FilterRule::exclude("*").unwrap()], ..Default::default() };
+        assert_eq!(matching(&filter, &items), ["a.log", "logs/b.log", "logs/old/c.log", "cache/d.log", "empty"]);
+        let filter = Filter { rules: vec![FilterRule::include("/logs/**").unwrap(), FilterRule::include("/logs/").unwrap(), FilterRule::exclude("*").unwrap()], ..Default::default() };
+        assert_eq!(matching(&filter, &items), ["logs/b.log", "logs/old/c.log"]);
+    }
+
+    #[test]
+    fn test_size_and_mtime() {
+        let items = [file("small", 10, 100), file("big", 1000, 200), FileEntry { relpath: PathBuf::from("old_server"), size: 10, ..Default::default() }];
+        let filter = Filter { min_size: Some(100), ..Default::default() };
+        assert_eq!(matching(&filter, &items), ["big"]);
+        let filter = Filter { max_size: Some(100), ..Default::default() };
+        assert_eq!(matching(&filter, &items), ["small", "old_server"]);
+        let filter = Filter { newer_than: Some(150), ..Default::default() };
+        assert_eq!(matching(&filter, &items), ["big", "old_server"]);
+    }
+}
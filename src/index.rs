@@ -0,0 +1,247 @@
+use std::collections::{ HashMap, HashSet, VecDeque };
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering::Relaxed };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+use serde::Serialize;
+use log::*;
+use crate::filelist::{ read_dir_contents, walk_tree, FileEntry, WalkError, WalkOptions };
+use crate::jbod::{ DirListing, Merge };
+
+/// In-memory copy of the source trees (serve --index), so that /list doesn't walk the disks every time.
+/// It's refreshed by reading again only the directories whose mtime has changed
+pub struct FileIndex {
+    src_paths: Vec<String>,
+    // per mount point, the mount points are scanned concurrently
+    walk: WalkOptions,
+    snapshot: Mutex<Option<Arc<Snapshot>>>,
+    // held during a scan, so that there is only one at a time
+    scanning: Mutex<()>,
+}
+
+/// The index as of its last scan
+pub struct Snapshot {
+    // one per mount point
+    mounts: Vec<MountDirs>,
+    // what couldn't be read, by every mount point
+    warnings: Vec<WalkError>,
+    pub scan: ScanInfo,
+    // when the last full scan has started, the files of the directories reused since may have changed in place
+    last_full: Instant,
+}
+
+// The directories of a mount point by relpath
+type MountDirs = HashMap<PathBuf, Arc<CachedDir>>;
+
+struct CachedDir {
+    // None if it has changed during the scan or couldn't be read entirely, so that the next one reads it again
+    mtime: Option<(i64, i64)>,
+    listing: Arc<DirListing>,
+}
+
+/// GET /index response
+#[derive(Serialize, Debug)]
+pub struct IndexStatus {
+    pub scanning: bool,
+    // None until the first scan is complete, /list walks the disks meanwhile
+    pub last_scan: Option<ScanInfo>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ScanInfo {
+    // changes made after the start of the scan aren't in the index
+    pub started_at: i64,
+    pub age_secs: u64,
+    pub duration_secs: f64,
+    // every directory has been read, whatever its mtime
+    pub full: bool,
+    pub dirs: usize,
+    pub dirs_read: usize,
+    pub entries: usize,
+    pub warnings: usize,
+}
+
+impl FileIndex {
+    /// Builds the index in the background and refreshes it every refresh_interval, reading everything again every full_rescan_interval
+    pub fn start(src_paths: Vec<String>, walk: WalkOptions, refresh_interval: Duration, full_rescan_interval: Option<Duration>) -> Arc<FileIndex> {
+        let index = Arc::new(FileIndex { src_paths, walk, snapshot: Mutex::new(None), scanning: Mutex::new(()) });
+        let refreshed = index.clone();
+        std::thread::spawn(move || loop {
+            let full = full_rescan_interval.is_some_and(|interval| refreshed.snapshot().is_some_and(|snapshot| snapshot.last_full.elapsed() >= interval));
+            if let Err(err) = refreshed.rescan(full) {
+                error!("Index refresh failed: {}", err);
+            }
+            std::thread::sleep(refresh_interval);
+        });
+        index
+    }
+    pub fn snapshot(&self) -> Option<Arc<Snapshot>> {
+        self.snapshot.lock().unwrap().clone()
+    }
+    pub fn status(&self) -> IndexStatus {
+        let last_scan = self.snapshot().map(|snapshot| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+            ScanInfo { age_secs: now.saturating_sub(snapshot.scan.started_at) as u64, ..snapshot.scan.clone() }
+        });
+        IndexStatus { scanning: self.scanning.try_lock().is_err(), last_scan }
+    }
+    /// A full rescan reads every directory again, e.g. after in-place changes which don't touch the mtime of the directories
+    pub fn rescan(&self, full: bool) -> io::Result<()> {
+        let _scanning = self.scanning.lock().unwrap();
+        let timer = Instant::now();
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        let previous = self.snapshot();
+        let full = full || previous.is_none();
+
+        let dirs_read = AtomicUsize::new(0);
+        let scanned: Vec<_> = std::thread::scope(|scope| {
+            let scanners: Vec<_> = self.src_paths.iter().enumerate().map(|(mount_idx, src_path)| {
+                let previous = previous.as_ref().filter(|_| !full).map(|snapshot| &snapshot.mounts[mount_idx]);
+                let dirs_read = &dirs_read;
+                scope.spawn(move || scan_mount(Path::new(src_path), previous, started_at, &self.walk, dirs_read))
+            }).collect();
+            scanners.into_iter().map(|scanner| scanner.join().unwrap()).collect::<io::Result<_>>()
+        })?;
+        let (mounts, warnings): (Vec<_>, Vec<Vec<_>>) = scanned.into_iter().unzip();
+        // Logged once rather than on every refresh
+        for (src_path, warnings) in self.src_paths.iter().zip(&warnings) {
+            for warning in warnings.iter().filter(|warning| previous.as_ref().is_none_or(|previous| !previous.warnings.contains(warning))) {
+                warn!("Couldn't index {}: {}", Path::new(src_path).join(&warning.path).display(), warning.error);
+            }
+        }
+        let warnings: Vec<WalkError> = warnings.into_iter().flatten().collect();
+        let scan = ScanInfo {
+            started_at,
+            age_secs: 0,
+            duration_secs: timer.elapsed().as_secs_f64(),
+            full,
+            dirs: mounts.iter().map(HashMap::len).sum(),
+            dirs_read: dirs_read.into_inner(),
+            entries: mounts.iter().flat_map(HashMap::values).map(|dir| dir.listing.contents.entries.len()).sum(),
+            warnings: warnings.len(),
+        };
+        if full {
+            info!("Index built: {} entries in {} directories, {:.1}s", scan.entries, scan.dirs, scan.duration_secs);
+        } else {
+            debug!("Index refreshed: {} of {} directories read, {:.1}s", scan.dirs_read, scan.dirs, scan.duration_secs);
+        }
+        let last_full = match &previous {
+            Some(previous) if !full => previous.last_full,
+            _ => timer,
+        };
+        *self.snapshot.lock().unwrap() = Some(Arc::new(Snapshot { mounts, warnings, scan, last_full }));
+        Ok(())
+    }
+}
+
+// The directories whose mtime hasn't changed since the previous scan are taken from it as they are
+fn scan_mount(base: &Path, previous: Option<&MountDirs>, started_at: i64, options: &WalkOptions, dirs_read: &AtomicUsize) -> io::Result<(MountDirs, Vec<WalkError>)> {
+    let base = match std::fs::canonicalize(base) {
+        Ok(base) => base,
+        Err(err) => return Ok((HashMap::new(), vec![WalkError::new(Path::new(""), err)])),
+    };
+    let dirs = Mutex::new(HashMap::new());
+    let warnings = walk_tree(&base, Path::new(""), options, |relpath, metadata, warnings| {
+        let mtime = (metadata.mtime(), metadata.mtime_nsec());
+        let dir = match previous.and_then(|previous| previous.get(relpath)) {
+            Some(dir) if dir.mtime == Some(mtime) => dir.clone(),
+            _ => {
+                dirs_read.fetch_add(1, Relaxed);
+                let mut contents = read_dir_contents(&base, &base.join(relpath));
+                let complete = contents.errors.is_empty();
+                warnings.append(&mut contents.errors);
+                Arc::new(CachedDir {
+                    // The mtime granularity may hide a change made right after the directory has been read
+                    mtime: (complete && metadata.mtime() < started_at).then_some(mtime),
+                    listing: Arc::new(DirListing { entry: FileEntry::from_metadata(relpath.to_path_buf(), metadata), contents }),
+                })
+            }
+        };
+        let subdirs = dir.listing.contents.subdirs.clone();
+        dirs.lock().unwrap().insert(relpath.to_path_buf(), dir);
+        Ok(subdirs)
+    })?;
+    Ok((dirs.into_inner().unwrap(), warnings))
+}
+
+impl Snapshot {
+    /// Same as jbod::walk_files, from the index
+    pub fn walk(&self, prefix: &Path, mut visit: impl FnMut(FileEntry) -> io::Result<()>) -> io::Result<Vec<WalkError>> {
+        // A directory is handed over by every mount point at once, so that it's merged right away
+        let mut merge = Merge::new(self.mounts.len(), prefix);
+        let mut queue: VecDeque<&Path> = VecDeque::from([prefix]);
+        let mut queued: HashSet<&Path> = HashSet::from([prefix]);
+        while let Some(relpath) = queue.pop_front() {
+            for (mount_idx, dirs) in self.mounts.iter().enumerate() {
+                if let Some(dir) = dirs.get(relpath) {
+                    queue.extend(dir.listing.contents.subdirs.iter().map(PathBuf::as_path).filter(|subdir| queued.insert(subdir)));
+                    merge.add_dir(mount_idx, dir.listing.clone(), &mut visit)?;
+                }
+            }
+        }
+        for mount_idx in 0..self.mounts.len() {
+            merge.mount_done(mount_idx, &mut visit)?;
+        }
+        // The ones within the prefix, or above it
+        Ok(self.warnings.iter().filter(|warning| warning.path.starts_with(prefix) || prefix.starts_with(&warning.path)).cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relpaths_and_sizes(snapshot: &Snapshot, prefix: &str) -> Vec<(PathBuf, u64)> {
+        let mut files = vec![];
+        snapshot.walk(Path::new(prefix), |item| {
+            files.push((item.relpath, item.size));
+            Ok(())
+        }).unwrap();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_rescan() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let (mount_point1, mount_point2) = (tempdir.path().join("1"), tempdir.path().join("2"));
+        std::fs::create_dir_all(mount_point1.join("somedir")).unwrap();
+        std::fs::create_dir_all(mount_point2.join("somedir")).unwrap();
+        std::fs::create_dir_all(mount_point2.join("otherdir")).unwrap();
+        std::fs::write(mount_point1.join("somedir/file.bin"), b"oneone").unwrap();
+        std::fs::write(mount_point2.join("somedir/file.bin"), b"oneoneone").unwrap();
+        std::fs::write(mount_point2.join("otherdir/file2.bin"), b"two").unwrap();
+        let src_paths = vec![mount_point1.to_str().unwrap().to_owned(), mount_point2.to_str().unwrap().to_owned()];
+        let index = FileIndex { src_paths: src_paths.clone(), walk: WalkOptions { threads: 2, ..Default::default() }, snapshot: Mutex::new(None), scanning: Mutex::new(()) };
+
+        index.rescan(false).unwrap();
+        let snapshot = index.snapshot().unwrap();
+        assert!(snapshot.scan.full);
+        assert_eq!(relpaths_and_sizes(&snapshot, ""), vec![(PathBuf::from("otherdir/file2.bin"), 3), (PathBuf::from("somedir/file.bin"), 9)]);
+        assert_eq!(relpaths_and_sizes(&snapshot, "otherdir"), vec![(PathBuf::from("otherdir/file2.bin"), 3)]);
+
+        // Same as walking the disks
+        let mut walked = vec![];
+        crate::jbod::walk_files(&src_paths, Path::new(""), &WalkOptions::default(), |item| {
+            walked.push((item.relpath, item.size));
+            Ok(())
+        }).unwrap();
+        walked.sort();
+        assert_eq!(relpaths_and_sizes(&snapshot, ""), walked);
+
+        // The directories are older than the scan, so they aren't read again unless their mtime changes
+        let old = SystemTime::now() - Duration::from_secs(10);
+        for dir in [&mount_point1, &mount_point2, &mount_point1.join("somedir"), &mount_point2.join("somedir"), &mount_point2.join("otherdir")] {
+            std::fs::File::open(dir).unwrap().set_modified(old).unwrap();
+        }
+        index.rescan(true).unwrap();
+        std::fs::write(mount_point1.join("somedir/new.bin"), b"new").unwrap();
+        index.rescan(false).unwrap();
+        let snapshot = index.snapshot().unwrap();
+        assert!(!snapshot.scan.full);
+        assert_eq!(snapshot.scan.dirs_read, 1);
+        assert_eq!(relpaths_and_sizes(&snapshot, "somedir"), vec![(PathBuf::from("somedir/file.bin"), 9), (PathBuf::from("somedir/new.bin"), 3)]);
+    }
+}
@@ -1,60 +1,265 @@
-use std::path::PathBuf;
-use std::collections::HashMap;
-use crate::filelist::{ list_files_bfs, FileEntry };
+use std::path::{ Path, PathBuf, Component };
+use std::collections::{ HashMap, BTreeMap };
+use std::sync::Arc;
+use std::io;
+use crate::filelist::{ walk_bfs, walk_tree, read_dir_contents, partial_path, is_partial, DirContents, FileEntry, HardLinks, WalkError, WalkOptions };
 use regex::Regex;
+use log::*;
 
-pub fn list_files(mount_points: &Vec<String>) -> Vec<FileEntry> {
-    let mut file_sizes: HashMap<PathBuf, u64> = HashMap::new();
-    let mut file_paths: Vec<PathBuf> = vec![];
-    for path in mount_points {
-        for item in list_files_bfs(std::path::Path::new(path)).unwrap() {
-            if !file_sizes.contains_key(&item.relpath) {
-                file_paths.push(item.relpath.clone());
-                file_sizes.insert(item.relpath.clone(), item.size);
+/// Walks the mount points concurrently, with threads per mount point, and hands the merged entries over as soon as they are found.
+/// An entry found on several mount points is listed once, see Merge. An empty prefix lists everything.
+/// Returns what couldn't be read on every mount point, after logging it
+pub fn walk_files(mount_points: &[String], prefix: &Path, options: &WalkOptions, mut visit: impl FnMut(FileEntry) -> io::Result<()>) -> io::Result<Vec<WalkError>> {
+    std::thread::scope(|scope| {
+        let (tx, rx) = std::sync::mpsc::sync_channel(WALK_CHANNEL_DIRS);
+        let walkers: Vec<_> = mount_points.iter().enumerate().map(|(mount_idx, path)| {
+            let tx = tx.clone();
+            scope.spawn(move || {
+                let walked = walk_mount(Path::new(path), prefix, options, |dir| {
+                    tx.send(WalkMessage::Dir(mount_idx, dir)).map_err(|_| io::Error::other("the listing has been stopped"))
+                });
+                let _ = tx.send(WalkMessage::Walked(mount_idx));
+                for warning in walked.iter().flatten() {
+                    warn!("Couldn't list {}: {}", Path::new(path).join(&warning.path).display(), warning.error);
+                }
+                walked
+            })
+        }).collect();
+        drop(tx);
+
+        // The walkers stop as soon as the channel is dropped
+        let mut merge = Merge::new(mount_points.len(), prefix);
+        let visited = rx.into_iter().try_for_each(|message| match message {
+            WalkMessage::Dir(mount_idx, dir) => merge.add_dir(mount_idx, dir, &mut visit),
+            WalkMessage::Walked(mount_idx) => merge.mount_done(mount_idx, &mut visit),
+        });
+        let walked: io::Result<Vec<Vec<WalkError>>> = walkers.into_iter().map(|walker| walker.join().unwrap()).collect();
+        visited.and(walked).map(|warnings| warnings.into_iter().flatten().collect())
+    })
+}
+
+enum WalkMessage {
+    Dir(usize, Arc<DirListing>),
+    // the walker of this mount point is done, whatever it hasn't sent isn't there
+    Walked(usize),
+}
+
+// Hands every directory of a mount point over to send, as it's read
+fn walk_mount(base: &Path, prefix: &Path, options: &WalkOptions, send: impl Fn(Arc<DirListing>) -> io::Result<()> + Sync) -> io::Result<Vec<WalkError>> {
+    let base = match std::fs::canonicalize(base) {
+        Ok(base) => base,
+        Err(err) => return Ok(vec![WalkError::new(Path::new(""), err)]),
+    };
+    walk_tree(&base, prefix, options, |relpath, metadata, warnings| {
+        let mut contents = read_dir_contents(&base, &base.join(relpath));
+        warnings.append(&mut contents.errors);
+        let subdirs = contents.subdirs.clone();
+        send(Arc::new(DirListing { entry: FileEntry::from_metadata(relpath.to_path_buf(), metadata), contents }))?;
+        Ok(subdirs)
+    })
+}
+
+/// A directory as read on one mount point
+pub struct DirListing {
+    // the directory itself, listed when it's empty
+    pub entry: FileEntry,
+    pub contents: DirContents,
+}
+
+/// Merges the directories read on every mount point, in whatever order they come, into a single list.
+/// A directory is merged once every mount point has either sent it or is known not to have it:
+/// the largest copy of an entry wins, the first mount point on a tie, and a file wins over a directory of the same relpath.
+/// A directory is listed only when it's empty on every mount point.
+/// The walkers aren't held back by the slowest one: the listings a mount point sent for directories another one hasn't reached yet
+/// are kept until it does, so with one mount point far behind this can take up to the other trees in memory (a few hundred bytes per entry)
+pub struct Merge {
+    walked: Vec<bool>,
+    pending: HashMap<PathBuf, PendingDir>,
+    // per mount point, a hard link and its target always come from the same one
+    hard_links: Vec<HardLinks>,
+}
+
+struct PendingDir {
+    // what every mount point has sent of it
+    listings: Vec<Option<Arc<DirListing>>>,
+    // the mount points whose parent directory holds it, known once the parent is merged
+    expected: Option<Vec<bool>>,
+    // a file of the same relpath is listed instead
+    shadowed: bool,
+}
+
+impl PendingDir {
+    fn new(mounts: usize) -> PendingDir {
+        PendingDir { listings: vec![None; mounts], expected: None, shadowed: false }
+    }
+}
+
+impl Merge {
+    pub fn new(mounts: usize, prefix: &Path) -> Merge {
+        let mut pending = HashMap::new();
+        pending.insert(prefix.to_path_buf(), PendingDir { expected: Some(vec![true; mounts]), ..PendingDir::new(mounts) });
+        Merge { walked: vec![false; mounts], pending, hard_links: (0..mounts).map(|_| HardLinks::default()).collect() }
+    }
+    pub fn add_dir(&mut self, mount_idx: usize, dir: Arc<DirListing>, visit: &mut impl FnMut(FileEntry) -> io::Result<()>) -> io::Result<()> {
+        let relpath = dir.entry.relpath.clone();
+        let mounts = self.walked.len();
+        self.pending.entry(relpath.clone()).or_insert_with(|| PendingDir::new(mounts)).listings[mount_idx] = Some(dir);
+        self.merge_ready(vec![relpath], visit)
+    }
+    pub fn mount_done(&mut self, mount_idx: usize, visit: &mut impl FnMut(FileEntry) -> io::Result<()>) -> io::Result<()> {
+        self.walked[mount_idx] = true;
+        let relpaths = self.pending.keys().cloned().collect();
+        self.merge_ready(relpaths, visit)
+    }
+    fn merge_ready(&mut self, mut queue: Vec<PathBuf>, visit: &mut impl FnMut(FileEntry) -> io::Result<()>) -> io::Result<()> {
+        while let Some(relpath) = queue.pop() {
+            let Some(dir) = self.pending.get(&relpath) else {
+                continue;
+            };
+            let Some(expected) = &dir.expected else {
+                continue;
+            };
+            let ready = expected.iter().zip(&dir.listings).zip(&self.walked).all(|((expected, listing), walked)| !expected || listing.is_some() || *walked);
+            if !ready {
+                continue;
+            }
+            let dir = self.pending.remove(&relpath).unwrap();
+            queue.extend(self.merge_dir(&relpath, dir, visit)?);
+        }
+        Ok(())
+    }
+    // Lists the entries of the directory, returns its subdirectories, which can be merged from now on
+    fn merge_dir(&mut self, relpath: &Path, dir: PendingDir, visit: &mut impl FnMut(FileEntry) -> io::Result<()>) -> io::Result<Vec<PathBuf>> {
+        let listings: Vec<(usize, &DirListing)> = dir.listings.iter().enumerate().filter_map(|(mount_idx, listing)| Some((mount_idx, listing.as_deref()?))).collect();
+
+        let entry = |(mount_idx, entry_idx): (usize, usize)| &dir.listings[mount_idx].as_ref().unwrap().contents.entries[entry_idx];
+        // by relpath, the mount point and the index of its largest copy
+        let mut largest: BTreeMap<&Path, (usize, usize)> = BTreeMap::new();
+        for &(mount_idx, listing) in &listings {
+            for (entry_idx, (item, _inode)) in listing.contents.entries.iter().enumerate() {
+                largest.entry(&item.relpath)
+                    .and_modify(|current| if item.size > entry(*current).0.size { *current = (mount_idx, entry_idx) })
+                    .or_insert((mount_idx, entry_idx));
             }
-            if file_sizes[&item.relpath] < item.size {
-                file_sizes.insert(item.relpath, item.size);
+        }
+        for &(mount_idx, entry_idx) in largest.values() {
+            let (item, inode) = entry((mount_idx, entry_idx));
+            let mut item = item.clone();
+            self.hard_links[mount_idx].assign(&mut item, *inode);
+            visit(item)?;
+        }
+        if !relpath.as_os_str().is_empty() && !dir.shadowed && listings.iter().all(|(_, listing)| listing.contents.is_empty)
+            && let Some((_, listing)) = listings.first() {
+            visit(listing.entry.clone())?;
+        }
+
+        let mounts = self.walked.len();
+        let mut subdirs = vec![];
+        for &(mount_idx, listing) in &listings {
+            for subdir in &listing.contents.subdirs {
+                let pending = self.pending.entry(subdir.clone()).or_insert_with(|| PendingDir::new(mounts));
+                if pending.expected.is_none() {
+                    pending.shadowed = largest.contains_key(subdir.as_path());
+                    subdirs.push(subdir.clone());
+                }
+                pending.expected.get_or_insert_with(|| vec![false; mounts])[mount_idx] = true;
             }
         }
+        Ok(subdirs)
+    }
+}
+
+pub fn find_file(mount_points: &[String], rel_path: &Path) -> Option<PathBuf> {
+    if is_partial(rel_path) {
+        return None;
     }
+    // Symlinks aren't followed, so that they can be found as they are
+    let mut candidates: Vec<_> = mount_points.iter()
+        .map(|path| PathBuf::from(path).join(rel_path))
+        .filter_map(|path| std::fs::symlink_metadata(&path).ok().map(|m| (path, m.len())))
+        .collect();
+    candidates.sort_by_key(|(_path, len)| *len);
+    candidates.pop().map(|(path, _len)| path)
+}
 
-    file_paths.into_iter().map(|relpath| {
-        let size = file_sizes[&relpath];
-        FileEntry { relpath, size }
-    }).collect()
+/// Same as find_file for a path requested by a client, it has to stay within the mount points once canonicalized.
+/// Symlinks pointing outside of them are refused unless allow_external_symlinks is set. Returns the canonical path
+pub fn find_served_file(mount_points: &[String], rel_path: &Path, allow_external_symlinks: bool) -> Option<PathBuf> {
+    // Neither absolute paths nor .. components, they would escape the mount points when joined
+    if !rel_path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+        warn!("Refusing a path outside of the source paths: {}", rel_path.display());
+        return None;
+    }
+    let path = std::fs::canonicalize(find_file(mount_points, rel_path)?).ok()?;
+    let inside = mount_points.iter().filter_map(|mount_point| std::fs::canonicalize(mount_point).ok()).any(|mount_point| path.starts_with(mount_point));
+    if !inside && !allow_external_symlinks {
+        warn!("Refusing a symlink to outside of the source paths: {} -> {}", rel_path.display(), path.display());
+        return None;
+    }
+    Some(path)
 }
 
-pub fn find_file(mount_points: &Vec<String>, rel_path: &PathBuf) -> Option<PathBuf> {
-    let file_exists = |path: &PathBuf| std::fs::exists(&path).unwrap_or(false);
-    let mut candidates: Vec<_> = mount_points.into_iter().map(|path| PathBuf::from(path).join(rel_path)).filter(file_exists).collect();
-    candidates.sort_by_key(|path| std::fs::metadata(&path).unwrap().len());
-    candidates.pop()
+// Looks for an unfinished download of rel_path, returns the path it's going to be renamed into
+pub fn find_partial(mount_points: &[String], rel_path: &Path) -> Option<PathBuf> {
+    let mut candidates: Vec<_> = mount_points.iter()
+        .map(|path| PathBuf::from(path).join(rel_path))
+        .filter_map(|path| std::fs::metadata(partial_path(&path)).ok().map(|m| (path, m.len())))
+        .collect();
+    candidates.sort_by_key(|(_path, len)| *len);
+    candidates.pop().map(|(path, _len)| path)
 }
 
 type AbsPath = PathBuf;
 
-pub fn index_by_regex(paths: &Vec<String>, regex: &Regex) -> HashMap<String, AbsPath> {
-    let mut ret: Vec<(String, PathBuf)> = vec![];
-    for path in paths {
-        let path = std::path::Path::new(path);
-        for item in list_files_bfs(path).unwrap() {
-            let filename = item.relpath.file_name().unwrap().to_string_lossy();
-            let captures = regex.captures(&filename);
-            if let Some(captures) = captures {
-                let key: &str = &captures[if captures.len() > 1 { 1 } else { 0 }];
-                ret.push((key.into(), path.into()));
+// Directories buffered between the walkers and the consumer of walk_files
+const WALK_CHANNEL_DIRS: usize = 256;
+
+/// Finds the files matching the regex on each mount point, walked concurrently, by the regex capture
+/// Files which couldn't be read are only logged, they are left out
+pub fn index_by_regex(paths: &[String], regex: &Regex, options: &WalkOptions) -> HashMap<String, AbsPath> {
+    let found: Vec<Vec<(String, AbsPath)>> = std::thread::scope(|scope| {
+        let walkers: Vec<_> = paths.iter().map(|path| scope.spawn(move || {
+            let mut found = vec![];
+            let walked = walk_bfs(Path::new(path), Path::new(""), options, |item| item.kind.is_file(), |item| {
+                let filename = item.relpath.file_name().unwrap().to_string_lossy();
+                if let Some(captures) = regex.captures(&filename) {
+                    let key: &str = &captures[if captures.len() > 1 { 1 } else { 0 }];
+                    found.push((key.into(), path.into()));
+                }
+                Ok(())
+            });
+            // visit never fails
+            for warning in walked.unwrap_or_default() {
+                warn!("Couldn't index {}: {}", Path::new(path).join(&warning.path).display(), warning.error);
             }
-        }
-    }
-    ret.into_iter().collect()
+            found
+        })).collect();
+        walkers.into_iter().map(|walker| walker.join().unwrap()).collect()
+    });
+    found.into_iter().flatten().collect()
 }
 
 #[cfg(test)]
 mod tests {
     use tempfile::{ tempdir, TempDir };
     use anyhow::Result;
+    use std::os::unix::fs::PermissionsExt;
+    use crate::filelist::EntryKind;
     use super::*;
 
+    fn list_files(mount_points: &[String], prefix: &Path) -> Vec<FileEntry> {
+        let mut files = vec![];
+        walk_files(mount_points, prefix, &WalkOptions { threads: 2, ..Default::default() }, |item| {
+            files.push(item);
+            Ok(())
+        }).unwrap();
+        files
+    }
+
+    fn relpaths_and_sizes(entries: &[FileEntry]) -> Vec<(PathBuf, u64)> {
+        entries.iter().map(|item| (item.relpath.clone(), item.size)).collect()
+    }
+
     #[allow(dead_code)]
     struct Fixture {
         tempdir: TempDir,
@@ -82,6 +287,13 @@ mod tests {
             std::fs::write(f.mount_point2.join("somedir/file2.bin"), b"twotwo")?;
             Ok(f)
         }
+        fn test_partial_files() -> Result<Fixture> {
+            let f = Fixture::create()?;
+            std::fs::write(f.mount_point1.join("somedir/file.bin"), b"oneone")?;
+            std::fs::write(f.mount_point2.join("somedir/file.bin.jbodncp.partial"), b"oneoneone")?;
+            std::fs::write(f.mount_point1.join("somedir/file2.bin.jbodncp.partial"), b"two")?;
+            Ok(f)
+        }
         fn test_regex_index() -> Result<Fixture> {
             let f = Fixture::create()?;
             std::fs::write(f.mount_point1.join("somedir/xlq7ocsbaxlm_h"), b"123456780")?;
@@ -98,13 +310,33 @@ mod tests {
     #[test]
     fn test_list_files() {
         let f = Fixture::test_merge_paths().unwrap();
-        let mut res = list_files(&f.mount_points);
+        let mut res = list_files(&f.mount_points, Path::new(""));
         res.sort_by_key(|x| x.relpath.clone());
 
-        assert_eq!(res, vec![
-            FileEntry { relpath: "somedir/file.bin".into(), size: 9 },
-            FileEntry { relpath: "somedir/file2.bin".into(), size: 9 },
+        assert_eq!(relpaths_and_sizes(&res), vec![
+            (PathBuf::from("somedir/file.bin"), 9),
+            (PathBuf::from("somedir/file2.bin"), 9),
         ]);
+        assert_eq!(res[0].mode, Some(std::fs::metadata(f.mount_point2.join("somedir/file.bin")).unwrap().permissions().mode() & 0o7777));
+        assert!(res[0].mtime.is_some());
+    }
+
+    #[test]
+    fn test_list_prefix() {
+        let f = Fixture::test_merge_paths().unwrap();
+        std::fs::create_dir_all(f.mount_point1.join("otherdir/somedir")).unwrap();
+        std::fs::write(f.mount_point1.join("otherdir/somedir/file.bin"), b"other").unwrap();
+        std::fs::write(f.mount_point2.join("somedir.bin"), b"not a dir").unwrap();
+
+        let mut res = list_files(&f.mount_points, Path::new("somedir"));
+        res.sort_by_key(|x| x.relpath.clone());
+        assert_eq!(relpaths_and_sizes(&res), vec![
+            (PathBuf::from("somedir/file.bin"), 9),
+            (PathBuf::from("somedir/file2.bin"), 9),
+        ]);
+        assert_eq!(relpaths_and_sizes(&list_files(&f.mount_points, Path::new("otherdir/somedir"))), vec![(PathBuf::from("otherdir/somedir/file.bin"), 5)]);
+        assert!(list_files(&f.mount_points, Path::new("somedir/file.bin")).is_empty());
+        assert!(list_files(&f.mount_points, Path::new("missing")).is_empty());
     }
 
     #[test]
@@ -115,16 +347,184 @@ mod tests {
         assert_eq!(find_file(&f.mount_points, &PathBuf::from("somedir/file.txt")), None);
     }
 
+    #[test]
+    fn test_partial_files_ignored() {
+        let f = Fixture::test_partial_files().unwrap();
+        assert_eq!(relpaths_and_sizes(&list_files(&f.mount_points, Path::new(""))), vec![(PathBuf::from("somedir/file.bin"), 6)]);
+        assert_eq!(find_file(&f.mount_points, &PathBuf::from("somedir/file.bin")), Some(f.mount_point1.join("somedir/file.bin")));
+        assert_eq!(find_file(&f.mount_points, &PathBuf::from("somedir/file2.bin")), None);
+        assert_eq!(find_file(&f.mount_points, &PathBuf::from("somedir/file2.bin.jbodncp.partial")), None);
+        assert_eq!(find_partial(&f.mount_points, &PathBuf::from("somedir/file2.bin")), Some(f.mount_point1.join("somedir/file2.bin")));
+    }
+
+    #[test]
+    fn test_list_special_entries() {
+        let f = Fixture::create().unwrap();
+        std::fs::write(f.mount_point1.join("somedir/file.bin"), b"oneone").unwrap();
+        std::os::unix::fs::symlink("file.bin", f.mount_point1.join("somedir/link")).unwrap();
+        std::os::unix::fs::symlink("../somedir", f.mount_point1.join("somedir/dirlink")).unwrap();
+        std::fs::create_dir_all(f.mount_point1.join("somedir/empty")).unwrap();
+
+        let mut res = list_files(&f.mount_points[..1], Path::new(""));
+        res.sort_by_key(|x| x.relpath.clone());
+        let kinds: Vec<_> = res.iter().map(|item| (item.relpath.to_str().unwrap(), item.kind, item.target.clone())).collect();
+        assert_eq!(kinds, vec![
+            ("somedir/dirlink", EntryKind::Symlink, Some(PathBuf::from("../somedir"))),
+            ("somedir/empty", EntryKind::Dir, None),
+            ("somedir/file.bin", EntryKind::File, None),
+            ("somedir/link", EntryKind::Symlink, Some(PathBuf::from("file.bin"))),
+        ]);
+    }
+
+    #[test]
+    fn test_list_hardlinks() {
+        let f = Fixture::create().unwrap();
+        std::fs::write(f.mount_point1.join("somedir/file.bin"), b"oneone").unwrap();
+        std::fs::hard_link(f.mount_point1.join("somedir/file.bin"), f.mount_point1.join("link.bin")).unwrap();
+        std::fs::write(f.mount_point1.join("somedir/file2.bin"), b"two").unwrap();
+        std::fs::hard_link(f.mount_point1.join("somedir/file2.bin"), f.mount_point1.join("link2.bin")).unwrap();
+        // file2.bin is taken from the second mount point, so link2.bin can't be a link to it anymore
+        std::fs::write(f.mount_point2.join("somedir/file2.bin"), b"twotwo").unwrap();
+
+        let mut res = list_files(&f.mount_points, Path::new(""));
+        res.sort_by_key(|x| x.relpath.clone());
+        let links: Vec<_> = res.iter().map(|item| (item.relpath.to_str().unwrap(), item.link.clone())).collect();
+        assert_eq!(links, vec![
+            ("link.bin", None),
+            ("link2.bin", None),
+            ("somedir/file.bin", Some(PathBuf::from("link.bin"))),
+            ("somedir/file2.bin", None),
+        ]);
+    }
+
+    #[test]
+    fn test_list_conflicts() {
+        let f = Fixture::create().unwrap();
+        // An empty directory on one mount point and a file on the other
+        std::fs::create_dir_all(f.mount_point1.join("somedir/empty")).unwrap();
+        std::fs::write(f.mount_point2.join("somedir/empty"), b"file").unwrap();
+        // Empty on one mount point only
+        std::fs::create_dir_all(f.mount_point1.join("somedir/half")).unwrap();
+        std::fs::create_dir_all(f.mount_point2.join("somedir/half")).unwrap();
+        std::fs::write(f.mount_point2.join("somedir/half/file.bin"), b"one").unwrap();
+        // Same size, the first mount point wins
+        std::fs::write(f.mount_point1.join("somedir/tie.bin"), b"one").unwrap();
+        std::fs::write(f.mount_point2.join("somedir/tie.bin"), b"two").unwrap();
+        std::fs::File::options().write(true).open(f.mount_point2.join("somedir/tie.bin")).unwrap()
+            .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1000)).unwrap();
+
+        let mut res = list_files(&f.mount_points, Path::new(""));
+        res.sort_by_key(|x| x.relpath.clone());
+        let kinds: Vec<_> = res.iter().map(|item| (item.relpath.to_str().unwrap(), item.kind, item.size)).collect();
+        assert_eq!(kinds, vec![
+            ("somedir/empty", EntryKind::File, 4),
+            ("somedir/half/file.bin", EntryKind::File, 3),
+            ("somedir/tie.bin", EntryKind::File, 3),
+        ]);
+        let tie = res.iter().find(|item| item.relpath == Path::new("somedir/tie.bin")).unwrap();
+        assert_ne!(tie.mtime, Some(1000));
+    }
+
+    #[test]
+    fn test_walk_threads() {
+        let f = Fixture::create().unwrap();
+        for (mount_point, count) in [(&f.mount_point1, 20), (&f.mount_point2, 10)] {
+            for i in 0..count {
+                let dir = mount_point.join(format!("somedir/{}/{}", i % 4, i));
+                std::fs::create_dir_all(&dir).unwrap();
+                std::fs::write(dir.join("file.bin"), vec![0; i + count]).unwrap();
+            }
+        }
+        std::fs::hard_link(f.mount_point1.join("somedir/0/0/file.bin"), f.mount_point1.join("somedir/3/3/link.bin")).unwrap();
+
+        let walk = |threads| {
+            let mut res = vec![];
+            walk_files(&f.mount_points, Path::new(""), &WalkOptions { threads, ..Default::default() }, |item| {
+                res.push((item.relpath, item.size, item.link.is_some() || item.linked));
+                Ok(())
+            }).unwrap();
+            res.sort();
+            res
+        };
+        let res = walk(1);
+        assert_eq!(res.len(), 21);
+        assert_eq!(res.iter().filter(|(_relpath, _size, linked)| *linked).count(), 2);
+        assert!(res.contains(&(PathBuf::from("somedir/1/1/file.bin"), 21, false)));
+        assert_eq!(walk(4), res);
+    }
+
+    #[test]
+    fn test_find_served_file() {
+        let f = Fixture::test_merge_paths().unwrap();
+        let outside = f.tempdir.path().join("outside");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("secret.bin"), b"secret").unwrap();
+        std::os::unix::fs::symlink(f.mount_point2.join("somedir/file.bin"), f.mount_point1.join("inside")).unwrap();
+        std::os::unix::fs::symlink(outside.join("secret.bin"), f.mount_point1.join("outside.bin")).unwrap();
+        std::os::unix::fs::symlink(&outside, f.mount_point1.join("outsidedir")).unwrap();
+        let find = |rel_path: &str, allow_external_symlinks| find_served_file(&f.mount_points, Path::new(rel_path), allow_external_symlinks);
+
+        let mount_point2 = std::fs::canonicalize(&f.mount_point2).unwrap();
+        assert_eq!(find("somedir/file.bin", false), Some(mount_point2.join("somedir/file.bin")));
+        assert_eq!(find("./somedir/file2.bin", false), Some(std::fs::canonicalize(&f.mount_point1).unwrap().join("somedir/file2.bin")));
+        assert_eq!(find("inside", false), Some(mount_point2.join("somedir/file.bin")));
+
+        // Nothing outside of the mount points, even if it exists
+        assert_eq!(find("../outside/secret.bin", false), None);
+        assert_eq!(find("somedir/../../outside/secret.bin", true), None);
+        assert_eq!(find(outside.join("secret.bin").to_str().unwrap(), true), None);
+        assert_eq!(find("outside.bin", false), None);
+        assert_eq!(find("outsidedir/secret.bin", false), None);
+
+        let secret = std::fs::canonicalize(outside.join("secret.bin")).unwrap();
+        assert_eq!(find("outside.bin", true), Some(secret.clone()));
+        assert_eq!(find("outsidedir/secret.bin", true), Some(secret));
+    }
+
+    #[test]
+    fn test_walk_warnings() {
+        let f = Fixture::create().unwrap();
+        std::fs::write(f.mount_point1.join("somedir/file.bin"), b"oneone").unwrap();
+        std::fs::remove_dir(f.mount_point2.join("somedir")).unwrap();
+        std::fs::create_dir_all(f.mount_point2.join("locked")).unwrap();
+        std::fs::write(f.mount_point2.join("locked/file2.bin"), b"two").unwrap();
+        std::os::unix::fs::symlink("somedir", f.mount_point1.join("dirlink")).unwrap();
+
+        // The prefix doesn't go through symlinks either
+        let mut files = vec![];
+        let warnings = walk_files(&f.mount_points, Path::new("dirlink"), &WalkOptions::default(), |item| {
+            files.push(item);
+            Ok(())
+        }).unwrap();
+        assert!(files.is_empty() && warnings.is_empty());
+
+        std::fs::set_permissions(f.mount_point2.join("locked"), std::fs::Permissions::from_mode(0o000)).unwrap();
+        // root reads it anyway
+        if std::fs::read_dir(f.mount_point2.join("locked")).is_ok() {
+            return;
+        }
+        let mut files = vec![];
+        let warnings = walk_files(&f.mount_points, Path::new(""), &WalkOptions::default(), |item| {
+            files.push(item.relpath);
+            Ok(())
+        }).unwrap();
+        std::fs::set_permissions(f.mount_point2.join("locked"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        files.sort();
+        assert_eq!(files, vec![PathBuf::from("dirlink"), PathBuf::from("somedir/file.bin")]);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].path, PathBuf::from("locked"));
+    }
+
     #[test]
     fn test_index_by_regex() {
         let f = Fixture::test_regex_index().unwrap();
         let regex = Regex::new(r"^\w{12}").unwrap();
-        let index = index_by_regex(&f.mount_points, &regex);
+        let index = index_by_regex(&f.mount_points, &regex, &WalkOptions::default());
         assert_eq!(&index["xlq7ocsbaxlm"], &f.mount_point1);
         assert_eq!(&index["5uglbek9o2or"], &f.mount_point2);
 
         let regex_with_captures = Regex::new(r"^(\w{12})_([a-z])$").unwrap();
-        let index2 = index_by_regex(&f.mount_points, &regex);
+        let index2 = index_by_regex(&f.mount_points, &regex_with_captures, &WalkOptions { threads: 2, ..Default::default() });
         assert_eq!(index2, index);
     }
 }
@@ -4,21 +4,38 @@ mod client;
 mod server;
 mod jbod;
 mod disk_space;
+mod checksum;
+mod ratelimit;
+mod progress;
+mod report;
+mod errors;
+mod metadata;
+mod compression;
+mod filter;
+mod mirror;
+mod verify;
+mod index;
 
-use clap::Parser;
 use client::run_client;
 use server::serve;
 use crate::cli::SubCommand::*;
 use log::error;
+use std::process::ExitCode;
 
-fn main() {
+fn main() -> ExitCode {
     logsy::set_echo(true);
-    let args = cli::RunArgs::parse();
+    let args = cli::RunArgs::parse_ordered();
+    if args.verbose {
+        logsy::set_level(log::LevelFilter::Debug);
+    }
     let result = match args.cmd {
-        Serve { src_paths, port } => Ok(serve(src_paths, port)),
+        Serve(args) => serve(args),
         Download(args) => run_client(args),
+        Verify(args) => verify::run_verify(args),
     };
     if let Err(err) = result {
         error!("Operation failed: {:#}", err);
+        return ExitCode::from(errors::exit_code(&err));
     }
+    ExitCode::SUCCESS
 }
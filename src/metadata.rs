@@ -0,0 +1,125 @@
+use anyhow::{ Result, Context };
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::Permissions;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use crate::filelist::{ FileEntry, EntryKind };
+
+/// Decides which owner the downloaded files get
+#[derive(Clone, Debug, Default)]
+pub struct Ownership {
+    pub is_root: bool,
+    pub uid_map: HashMap<u32, u32>,
+    pub gid_map: HashMap<u32, u32>,
+}
+
+impl Ownership {
+    pub fn new(uid_map: &[(u32, u32)], gid_map: &[(u32, u32)]) -> Ownership {
+        Ownership {
+            is_root: unsafe { libc::geteuid() } == 0,
+            uid_map: uid_map.iter().copied().collect(),
+            gid_map: gid_map.iter().copied().collect(),
+        }
+    }
+    // As root, every id is preserved unless it's mapped. Otherwise, only the mapped ones are applied
+    fn map(&self, map: &HashMap<u32, u32>, id: Option<u32>) -> Option<u32> {
+        let id = id?;
+        map.get(&id).copied().or(self.is_root.then_some(id))
+    }
+}
+
+/// Parses "FROM:TO" pairs passed to --uid-map and --gid-map
+pub fn parse_id_mapping(value: &str) -> Result<(u32, u32), String> {
+    let (from, to) = value.split_once(':').ok_or_else(|| format!("FROM:TO expected: {value}"))?;
+    let parse = |id: &str| id.parse::<u32>().map_err(|_| format!("invalid id: {id}"));
+    Ok((parse(from)?, parse(to)?))
+}
+
+/// Applies ownership, mtime and permissions of the original file, in this order
+/// (chown may reset setuid bits, and a read-only file can't be opened to set its mtime)
+pub fn apply_metadata(path: &Path, item: &FileEntry, ownership: &Ownership) -> Result<()> {
+    let uid = ownership.map(&ownership.uid_map, item.uid);
+    let gid = ownership.map(&ownership.gid_map, item.gid);
+    if uid.is_some() || gid.is_some() {
+        std::os::unix::fs::lchown(path, uid, gid).context("chown")?;
+    }
+    if let Some(mtime) = item.mtime {
+        let nsec = Duration::from_nanos(item.mtime_nsec.unwrap_or(0).into());
+        let mtime = if mtime >= 0 {
+            UNIX_EPOCH + Duration::from_secs(mtime as u64) + nsec
+        } else {
+            UNIX_EPOCH - Duration::from_secs(mtime.unsigned_abs()) + nsec
+        };
+        set_mtime(path, mtime).context("Setting mtime")?;
+    }
+    // Symlink permissions can't be changed, nor they matter
+    if let Some(mode) = item.mode.filter(|_| item.kind != EntryKind::Symlink) {
+        std::fs::set_permissions(path, Permissions::from_mode(mode)).context("chmod")?;
+    }
+    Ok(())
+}
+
+// Works on any kind of entry, symlinks are not followed
+fn set_mtime(path: &Path, mtime: SystemTime) -> std::io::Result<()> {
+    let since_epoch = |t: SystemTime| t.duration_since(UNIX_EPOCH).map(|d| (d.as_secs() as i64, d.subsec_nanos() as i64))
+        .unwrap_or_else(|e| (-(e.duration().as_secs() as i64), 0));
+    let (secs, nsecs) = since_epoch(mtime);
+    let times = [
+        libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+        libc::timespec { tv_sec: secs as libc::time_t, tv_nsec: nsecs as _ },
+    ];
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_apply_metadata() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+        std::fs::write(&path, b"oneone").unwrap();
+
+        let item = FileEntry { relpath: "file.bin".into(), size: 6, mtime: Some(1234567890), mtime_nsec: Some(500), mode: Some(0o640), ..Default::default() };
+        apply_metadata(&path, &item, &Ownership::default()).unwrap();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!((metadata.mtime(), metadata.mtime_nsec()), (1234567890, 500));
+        assert_eq!(metadata.mode() & 0o7777, 0o640);
+    }
+
+    #[test]
+    fn test_apply_metadata_symlink() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("link");
+        std::os::unix::fs::symlink("missing", &path).unwrap();
+
+        let item = FileEntry { relpath: "link".into(), kind: EntryKind::Symlink, mtime: Some(1234567890), mode: Some(0o777), ..Default::default() };
+        apply_metadata(&path, &item, &Ownership::default()).unwrap();
+        assert_eq!(std::fs::symlink_metadata(&path).unwrap().mtime(), 1234567890);
+    }
+
+    #[test]
+    fn test_ownership_mapping() {
+        let ownership = Ownership { is_root: false, uid_map: [(1000, 2000)].into(), gid_map: HashMap::new() };
+        assert_eq!(ownership.map(&ownership.uid_map, Some(1000)), Some(2000));
+        assert_eq!(ownership.map(&ownership.uid_map, Some(1001)), None);
+        assert_eq!(ownership.map(&ownership.uid_map, None), None);
+
+        let root = Ownership { is_root: true, ..ownership };
+        assert_eq!(root.map(&root.uid_map, Some(1000)), Some(2000));
+        assert_eq!(root.map(&root.uid_map, Some(1001)), Some(1001));
+        assert_eq!(parse_id_mapping("1000:2000"), Ok((1000, 2000)));
+        assert!(parse_id_mapping("1000").is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use anyhow::Result;
+use std::collections::HashSet;
+use std::path::{ Path, PathBuf };
+use log::*;
+use crate::filelist::{ list_files_bfs, EntryKind };
+use crate::filter::Filter;
+use crate::jbod;
+
+/// What --delete is going to remove from the destination mounts
+#[derive(Default, Debug)]
+pub struct DeletePlan {
+    // (mount point, relpath) of the entries which aren't on the server
+    pub extraneous: Vec<(PathBuf, PathBuf)>,
+    // (mount point, relpath) of the copies of a listed file other than the one jbod::find_file picks
+    pub duplicates: Vec<(PathBuf, PathBuf)>,
+    // number of entries found under the destination mounts
+    pub dst_entries: usize,
+}
+
+impl DeletePlan {
+    pub fn len(&self) -> usize {
+        self.extraneous.len() + self.duplicates.len()
+    }
+    pub fn percent(&self) -> f64 {
+        if self.dst_entries == 0 { 0.0 } else { self.len() as f64 * 100.0 / self.dst_entries as f64 }
+    }
+}
+
+/// Looks for the entries which don't belong to the destination, the ones left out by --include and --exclude are kept.
+/// The size and mtime filters don't count, a local copy may not match them while the server's one does
+pub fn plan_deletions(dst_paths: &[String], prefix: &Path, listed: &HashSet<PathBuf>, filter: &Filter) -> Result<DeletePlan> {
+    let mut plan = DeletePlan::default();
+    for mount_point in dst_paths {
+        let mount_point = PathBuf::from(mount_point);
+        for item in list_files_bfs(&mount_point, prefix)? {
+            plan.dst_entries += 1;
+            if !listed.contains(&item.relpath) {
+                if filter.matches_path(&item) {
+                    plan.extraneous.push((mount_point.clone(), item.relpath));
+                }
+            } else if item.kind != EntryKind::Dir && jbod::find_file(dst_paths, &item.relpath).is_some_and(|path| path != mount_point.join(&item.relpath)) {
+                plan.duplicates.push((mount_point.clone(), item.relpath));
+            }
+        }
+    }
+    Ok(plan)
+}
+
+/// Removes the planned entries, along with the directories left empty which aren't on the server either
+pub fn delete(plan: &DeletePlan, listed: &HashSet<PathBuf>, dry_run: bool) -> Result<u64> {
+    let kept_dirs: HashSet<&Path> = listed.iter().flat_map(|relpath| relpath.ancestors()).collect();
+    let mut deleted = 0;
+    for (mount_point, relpath) in plan.extraneous.iter().chain(&plan.duplicates) {
+        let path = mount_point.join(relpath);
+        if dry_run {
+            info!("Would delete: {}", path.display());
+            continue;
+        }
+        info!("Deleting: {}", path.display());
+        if std::fs::symlink_metadata(&path)?.is_dir() {
+            std::fs::remove_dir(&path)?;
+        } else {
+            std::fs::remove_file(&path)?;
+        }
+        deleted += 1;
+
+        let mut parent = relpath.parent();
+        while let Some(dir) = parent.filter(|dir| !dir.as_os_str().is_empty() && !kept_dirs.contains(dir)) {
+            // Fails as soon as a directory isn't empty
+            if std::fs::remove_dir(mount_point.join(dir)).is_err() {
+                break;
+            }
+            debug!("Deleted empty directory: {}", mount_point.join(dir).display());
+            parent = dir.parent();
+        }
+    }
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::FilterRule;
+
+    #[test]
+    fn test_delete() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let (mount_point1, mount_point2) = (tempdir.path().join("1"), tempdir.path().join("2"));
+        let dst_paths = vec![mount_point1.to_str().unwrap().to_owned(), mount_point2.to_str().unwrap().to_owned()];
+        std::fs::create_dir_all(mount_point1.join("keep")).unwrap();
+        std::fs::create_dir_all(mount_point1.join("gone/deeper")).unwrap();
+        std::fs::create_dir_all(mount_point2.join("keep")).unwrap();
+        std::fs::write(mount_point1.join("keep/file.bin"), b"complete").unwrap();
+        std::fs::write(mount_point2.join("keep/file.bin"), b"short").unwrap();
+        std::fs::write(mount_point2.join("keep/extra.bin"), b"extra").unwrap();
+        std::fs::write(mount_point1.join("gone/deeper/old.bin"), b"old").unwrap();
+        std::fs::write(mount_point1.join("local.cfg"), b"excluded").unwrap();
+
+        let listed: HashSet<PathBuf> = [PathBuf::from("keep/file.bin")].into();
+        // The size filters don't keep anything, only the patterns do
+        let filter = Filter { rules: vec![FilterRule::exclude("*.cfg").unwrap()], min_size: Some(100), ..Default::default() };
+        let plan = plan_deletions(&dst_paths, Path::new(""), &listed, &filter).unwrap();
+        assert_eq!(plan.extraneous.len(), 2);
+        assert_eq!(plan.duplicates, vec![(mount_point2.clone(), PathBuf::from("keep/file.bin"))]);
+        assert_eq!(plan.dst_entries, 5);
+        assert_eq!(plan.percent(), 60.0);
+
+        assert_eq!(delete(&plan, &listed, true).unwrap(), 0);
+        assert!(mount_point2.join("keep/extra.bin").exists());
+
+        assert_eq!(delete(&plan, &listed, false).unwrap(), 3);
+        assert!(mount_point1.join("keep/file.bin").exists());
+        assert!(mount_point1.join("local.cfg").exists());
+        assert!(!mount_point2.join("keep/file.bin").exists());
+        assert!(!mount_point2.join("keep/extra.bin").exists());
+        // Not on the server, so it's removed once empty, unlike "keep"
+        assert!(!mount_point1.join("gone").exists());
+        assert!(mount_point2.join("keep").exists());
+    }
+}
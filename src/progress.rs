@@ -0,0 +1,197 @@
+use std::io::{ self, IsTerminal, Read, Write };
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicU64, Ordering::Relaxed };
+use std::sync::mpsc::{ channel, Sender, RecvTimeoutError };
+use std::thread::JoinHandle;
+use std::time::{ Duration, Instant };
+use log::*;
+
+/// Transfer counters shared by the workers and the reporter thread
+pub struct Progress {
+    dst_paths: Vec<String>,
+    total_files: AtomicU64,
+    total_bytes: AtomicU64,
+    done_files: AtomicU64,
+    done_bytes: AtomicU64,
+    // bytes written into each destination mount
+    written: Vec<AtomicU64>,
+}
+
+impl Progress {
+    pub fn new(dst_paths: &[String]) -> Progress {
+        Progress {
+            dst_paths: dst_paths.to_vec(),
+            total_files: AtomicU64::new(0),
+            total_bytes: AtomicU64::new(0),
+            done_files: AtomicU64::new(0),
+            done_bytes: AtomicU64::new(0),
+            written: dst_paths.iter().map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+    pub fn add_total(&self, files: u64, bytes: u64) {
+        self.total_files.fetch_add(files, Relaxed);
+        self.total_bytes.fetch_add(bytes, Relaxed);
+    }
+    pub fn file_done(&self) {
+        self.done_files.fetch_add(1, Relaxed);
+    }
+    /// Accounts for the bytes which are already in place and don't need a transfer
+    pub fn skipped(&self, bytes: u64) {
+        self.done_bytes.fetch_add(bytes, Relaxed);
+    }
+    pub fn written(&self) -> Vec<(String, u64)> {
+        self.dst_paths.iter().cloned().zip(self.written.iter().map(|w| w.load(Relaxed))).collect()
+    }
+    pub fn reader<R: Read>(self: &Arc<Self>, inner: R, dst_path: &Path) -> ProgressReader<R> {
+        let disk = self.dst_paths.iter().position(|path| dst_path.starts_with(path));
+        ProgressReader { inner, progress: self.clone(), disk }
+    }
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            at: Instant::now(),
+            total_files: self.total_files.load(Relaxed),
+            total_bytes: self.total_bytes.load(Relaxed),
+            done_files: self.done_files.load(Relaxed),
+            done_bytes: self.done_bytes.load(Relaxed),
+            written: self.written.iter().map(|w| w.load(Relaxed)).collect(),
+        }
+    }
+}
+
+/// Counts the bytes going through it as transferred into the destination file
+pub struct ProgressReader<R> {
+    inner: R,
+    progress: Arc<Progress>,
+    disk: Option<usize>,
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.progress.done_bytes.fetch_add(len as u64, Relaxed);
+        if let Some(disk) = self.disk {
+            self.progress.written[disk].fetch_add(len as u64, Relaxed);
+        }
+        Ok(len)
+    }
+}
+
+struct Snapshot {
+    at: Instant,
+    total_files: u64,
+    total_bytes: u64,
+    done_files: u64,
+    done_bytes: u64,
+    written: Vec<u64>,
+}
+
+impl Snapshot {
+    fn transferred(&self) -> u64 {
+        self.written.iter().sum()
+    }
+}
+
+pub struct Reporter {
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Reporter {
+    /// Draws a progress bar on stderr if it's a terminal, otherwise logs a line every `interval`
+    pub fn start(progress: Arc<Progress>, interval: Duration) -> Reporter {
+        let (stop, stopped) = channel();
+        let tty = io::stderr().is_terminal();
+        let interval = if tty { Duration::from_secs(1) } else { interval };
+        let thread = std::thread::spawn(move || {
+            let start = progress.snapshot();
+            let mut prev = progress.snapshot();
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let current = progress.snapshot();
+                let line = format_progress(&progress.dst_paths, &start, &prev, &current);
+                if tty {
+                    eprint!("\r\x1b[K{}", line);
+                } else {
+                    info!("Progress: {}", line);
+                }
+                prev = current;
+            }
+            if tty {
+                eprint!("\r\x1b[K");
+                let _ = io::stderr().flush();
+            }
+        });
+        Reporter { stop, thread }
+    }
+    pub fn stop(self) {
+        drop(self.stop);
+        let _ = self.thread.join();
+    }
+}
+
+fn format_progress(dst_paths: &[String], start: &Snapshot, prev: &Snapshot, current: &Snapshot) -> String {
+    let rate = |from: u64, to: u64, elapsed: Duration| (to.saturating_sub(from)) as f64 / elapsed.as_secs_f64().max(0.001);
+    let elapsed = current.at - prev.at;
+    let current_rate = rate(prev.transferred(), current.transferred(), elapsed);
+    let average_rate = rate(start.transferred(), current.transferred(), current.at - start.at);
+
+    let percent = if current.total_bytes > 0 { (current.done_bytes as f64 * 100.0 / current.total_bytes as f64).min(100.0) } else { 100.0 };
+    let remaining = current.total_bytes.saturating_sub(current.done_bytes);
+    let eta = if average_rate >= 1.0 { format_duration(Duration::from_secs_f64(remaining as f64 / average_rate)) } else { "-".into() };
+
+    let disks: Vec<String> = dst_paths.iter().enumerate()
+        .map(|(i, path)| format!("{} {}/s", path, format_bytes(rate(prev.written[i], current.written[i], elapsed) as u64)))
+        .collect();
+
+    format!("{:.1}% {} / {}, files {} / {}, {}/s, ETA {} [{}]",
+        percent, format_bytes(current.done_bytes.min(current.total_bytes)), format_bytes(current.total_bytes),
+        current.done_files, current.total_files, format_bytes(current_rate as u64), eta, disks.join(", "))
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} B", bytes) } else { format!("{:.1} {}", value, UNITS[unit]) }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m{:02}s", secs / 3600, secs % 3600 / 60, secs % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formatting() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(200 << 20), "200.0 MiB");
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(format_duration(Duration::from_secs(3 * 60 + 5)), "3m05s");
+        assert_eq!(format_duration(Duration::from_secs(26 * 3600 + 61)), "26h01m01s");
+    }
+
+    #[test]
+    fn test_progress_reader() {
+        let progress = Arc::new(Progress::new(&["/pool1".into(), "/pool2".into()]));
+        progress.add_total(2, 10);
+        progress.skipped(4);
+        let mut reader = progress.reader(&b"123456"[..], Path::new("/pool2/somedir/file.bin"));
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert_eq!(progress.written(), vec![("/pool1".into(), 0), ("/pool2".into(), 6)]);
+
+        let snapshot = progress.snapshot();
+        assert_eq!((snapshot.done_bytes, snapshot.total_bytes, snapshot.transferred()), (10, 10, 6));
+    }
+}
@@ -0,0 +1,138 @@
+use std::io::{ self, Read };
+use std::str::FromStr;
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+use crate::cli::parse_size;
+
+/// Bandwidth limit, either a single rate ("200M") or a time-of-day schedule ("08:00,200M 18:00,off")
+#[derive(Clone, Debug, PartialEq)]
+pub struct BwSchedule(Vec<(u32, Option<u64>)>);
+
+impl BwSchedule {
+    /// Rate in bytes per second at the given minute of the day, None means unlimited
+    pub fn rate_at(&self, minute: u32) -> Option<u64> {
+        // Before the first entry of the day, the last one of the previous day still applies
+        let entry = self.0.iter().rev().find(|(start, _)| *start <= minute).or(self.0.last());
+        entry.and_then(|(_, rate)| *rate)
+    }
+}
+
+impl FromStr for BwSchedule {
+    type Err = String;
+    fn from_str(value: &str) -> Result<BwSchedule, String> {
+        let parse_rate = |rate: &str| match rate {
+            "off" | "0" => Ok(None),
+            rate => parse_size(rate).map(Some),
+        };
+        if !value.contains(',') {
+            return Ok(BwSchedule(vec![(0, parse_rate(value.trim())?)]));
+        }
+
+        let mut entries = vec![];
+        for item in value.split_whitespace() {
+            let (time, rate) = item.split_once(',').ok_or_else(|| format!("invalid schedule entry: {item}"))?;
+            let (hours, minutes) = time.split_once(':').ok_or_else(|| format!("invalid time: {time}"))?;
+            let hours: u32 = hours.parse().map_err(|_| format!("invalid time: {time}"))?;
+            let minutes: u32 = minutes.parse().map_err(|_| format!("invalid time: {time}"))?;
+            if hours > 23 || minutes > 59 {
+                return Err(format!("invalid time: {time}"));
+            }
+            entries.push((hours * 60 + minutes, parse_rate(rate)?));
+        }
+        entries.sort_by_key(|(start, _)| *start);
+        Ok(BwSchedule(entries))
+    }
+}
+
+fn local_minute_of_day() -> u32 {
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&now, &mut tm) };
+    (tm.tm_hour * 60 + tm.tm_min) as u32
+}
+
+/// Token bucket shared by all the transfers, allows bursts of up to one second worth of traffic
+pub struct RateLimiter {
+    schedule: BwSchedule,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(schedule: BwSchedule) -> RateLimiter {
+        RateLimiter { schedule, bucket: Mutex::new((0.0, Instant::now())) }
+    }
+    /// Takes `bytes` out of the bucket and returns how long the caller has to wait before sending them
+    pub fn reserve(&self, bytes: u64) -> Duration {
+        let Some(rate) = self.schedule.rate_at(local_minute_of_day()) else {
+            return Duration::ZERO;
+        };
+        self.reserve_at(bytes, rate as f64, Instant::now())
+    }
+    fn reserve_at(&self, bytes: u64, rate: f64, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let (tokens, last) = *bucket;
+        let tokens = (tokens + now.saturating_duration_since(last).as_secs_f64() * rate).min(rate) - bytes as f64;
+        *bucket = (tokens, now.max(last));
+        if tokens >= 0.0 { Duration::ZERO } else { Duration::from_secs_f64(-tokens / rate) }
+    }
+}
+
+/// Keeps the reads within the limit by sleeping after each one
+pub struct LimitedReader<R> {
+    inner: R,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl<R: Read> LimitedReader<R> {
+    pub fn new(inner: R, limiter: Option<Arc<RateLimiter>>) -> LimitedReader<R> {
+        LimitedReader { inner, limiter }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(limiter) = &self.limiter else {
+            return self.inner.read(buf);
+        };
+        // Smaller reads keep the traffic smooth
+        let max_len = buf.len().min(64 << 10);
+        let len = self.inner.read(&mut buf[..max_len])?;
+        std::thread::sleep(limiter.reserve(len as u64));
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let single: BwSchedule = "200M".parse().unwrap();
+        assert_eq!(single.rate_at(0), Some(200 << 20));
+        assert_eq!(single.rate_at(1000), Some(200 << 20));
+
+        let schedule: BwSchedule = "18:00,off 08:00,200M 12:30,1G".parse().unwrap();
+        assert_eq!(schedule.rate_at(7 * 60), None);
+        assert_eq!(schedule.rate_at(8 * 60), Some(200 << 20));
+        assert_eq!(schedule.rate_at(12 * 60 + 29), Some(200 << 20));
+        assert_eq!(schedule.rate_at(12 * 60 + 30), Some(1 << 30));
+        assert_eq!(schedule.rate_at(23 * 60), None);
+
+        assert!("25:00,1M".parse::<BwSchedule>().is_err());
+        assert!("08:00,1M 12:00".parse::<BwSchedule>().is_err());
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new("1M".parse().unwrap());
+        let start = Instant::now();
+        *limiter.bucket.lock().unwrap() = (0.0, start);
+        assert_eq!(limiter.reserve_at(1000, 1000.0, start), Duration::from_secs(1));
+        assert_eq!(limiter.reserve_at(1000, 1000.0, start), Duration::from_secs(2));
+        assert_eq!(limiter.reserve_at(1000, 1000.0, start + Duration::from_secs(3)), Duration::ZERO);
+        // Bursts are capped by one second worth of tokens
+        assert_eq!(limiter.reserve_at(1000, 1000.0, start + Duration::from_secs(100)), Duration::ZERO);
+        assert_eq!(limiter.reserve_at(1000, 1000.0, start + Duration::from_secs(100)), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,45 @@
+use anyhow::{ Result, Context };
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use crate::filelist::WalkError;
+
+/// Summary of a download run, written by --report for the scripts driving the transfers
+#[derive(Serialize, Debug, Default)]
+pub struct Report {
+    pub files_seen: u64,
+    pub downloaded: u64,
+    pub errors: u64,
+    pub skipped: u64,
+    // left out by --include, --exclude and the other filters
+    pub filtered: u64,
+    // removed by --delete
+    pub deleted: u64,
+    pub bytes_transferred: u64,
+    pub duration_secs: f64,
+    pub bytes_per_mount: BTreeMap<String, u64>,
+    // special files which couldn't be replicated
+    pub unsupported: Vec<PathBuf>,
+    // directories and entries the server couldn't read, they are missing from the transfer
+    pub list_warnings: Vec<WalkError>,
+    pub failed: Vec<FailedFile>,
+    // why the run has stopped or failed, None if everything went fine
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FailedFile {
+    pub relpath: PathBuf,
+    pub error: String,
+}
+
+impl Report {
+    pub fn write(&self, path: &str) -> Result<()> {
+        write_json(self, path)
+    }
+}
+
+pub fn write_json<T: Serialize>(report: &T, path: &str) -> Result<()> {
+    let json = serde_json::to_string_pretty(report)?;
+    std::fs::write(path, json).with_context(|| format!("Couldn't write report: {}", path))
+}
@@ -1,60 +1,537 @@
 use axum::{
     response::{IntoResponse, Response},
     middleware::{ Next, from_fn_with_state },
-    extract::{ Request, State, Path },
-    routing::get,
+    extract::{ Request, State, Path, ConnectInfo, Query },
+    body::{ Body, Bytes },
+    routing::{ get, post },
     Router,
     Json,
 };
 use tokio_util::io::ReaderStream;
-use std::path::PathBuf;
+use futures_util::StreamExt;
+use std::path::{ PathBuf, Component };
+use std::io::SeekFrom;
+use std::net::{ IpAddr, SocketAddr };
 use tokio::fs::File;
-use http::{header, StatusCode, HeaderValue};
+use tokio::io::{ AsyncRead, AsyncReadExt, AsyncSeekExt, ReadBuf };
+use http::{header, StatusCode, HeaderValue, HeaderMap};
+use std::collections::{ HashMap, BTreeMap };
+use std::sync::{ Arc, Mutex };
+use std::pin::Pin;
+use std::task::{ Context as TaskContext, Poll, ready };
+use std::time::{ Duration, SystemTime };
 use crate::jbod;
+use crate::checksum;
+use crate::compression::{ self, Encoder, Encoding };
+use crate::filelist::{ FileEntry, ListLine, ListSummary, WalkError, WalkOptions, NDJSON };
+use crate::index::FileIndex;
+use crate::disk_space;
+use crate::cli::{ ServeConfig, parse_size };
+use crate::filter::{ Filter, FilterRule, FILTERED_HEADER };
+use crate::ratelimit::RateLimiter;
 use rand::{distr::Alphanumeric, Rng};
 use log::*;
+use anyhow::{ Result, Context };
+
+// Checksums of the files served lately, see file_checksum. The least recently used ones are dropped past capacity
+struct ChecksumCache {
+    entries: HashMap<PathBuf, (u64, Arc<CachedChecksum>)>,
+    // the paths by last use
+    lru: BTreeMap<u64, PathBuf>,
+    uses: u64,
+    capacity: usize,
+}
+
+// A few tens of MB at most
+const CHECKSUM_CACHE_ENTRIES: usize = 100_000;
+
+impl Default for ChecksumCache {
+    fn default() -> ChecksumCache {
+        ChecksumCache { entries: HashMap::new(), lru: BTreeMap::new(), uses: 0, capacity: CHECKSUM_CACHE_ENTRIES }
+    }
+}
+
+// The checksum of a file as of the size and mtime it's computed for.
+// Concurrent requests for the same file (e.g. the segments of a large one) wait for a single computation
+struct CachedChecksum {
+    len: u64,
+    mtime: SystemTime,
+    checksum: tokio::sync::OnceCell<String>,
+}
 
 #[derive(Clone)]
 struct AppState {
     token: String,
     src_paths: Vec<String>,
+    checksums: Option<Arc<Mutex<ChecksumCache>>>,
+    index: Option<Arc<FileIndex>>,
+    walk: WalkOptions,
+    allow_external_symlinks: bool,
+    limits: Arc<StreamLimits>,
+}
+
+// The streamed list is sent in chunks of about this size
+const LIST_CHUNK_SIZE: usize = 64 * 1024;
+// Chunks buffered ahead of the client before the walk is paused
+const LIST_CHANNEL_CHUNKS: usize = 16;
+
+// How long the clients are asked to wait when the stream limits are hit
+const RETRY_AFTER_SECS: u64 = 1;
+
+struct StreamLimits {
+    per_client: Option<usize>,
+    total: Option<usize>,
+    bwlimit: Option<Arc<RateLimiter>>,
+    active: Mutex<HashMap<IpAddr, usize>>,
+}
+
+// Holds a slot in StreamLimits until the response body is dropped
+struct StreamGuard {
+    limits: Arc<StreamLimits>,
+    ip: IpAddr,
+}
+
+impl StreamLimits {
+    fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<StreamGuard> {
+        let mut active = self.active.lock().unwrap();
+        let total: usize = active.values().sum();
+        let client = active.get(&ip).copied().unwrap_or(0);
+        if self.total.is_some_and(|max| total >= max) || self.per_client.is_some_and(|max| client >= max) {
+            return None;
+        }
+        *active.entry(ip).or_insert(0) += 1;
+        Some(StreamGuard { limits: self.clone(), ip })
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let mut active = self.limits.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.ip);
+            }
+        }
+    }
+}
+
+fn limited_body<R: AsyncRead + Send + 'static>(reader: R, guard: StreamGuard, encoder: Option<Encoder>) -> Body {
+    let limiter = guard.limits.bwlimit.clone();
+    // Compression goes first, so that the limit applies to the bytes on the wire
+    let stream = match encoder {
+        Some(encoder) => compression::encode_stream(ReaderStream::new(reader), encoder).boxed(),
+        None => ReaderStream::new(reader).boxed(),
+    };
+    let stream = stream.then(move |chunk| {
+        let _guard = &guard;
+        let delay = match (&chunk, &limiter) {
+            (Ok(bytes), Some(limiter)) => limiter.reserve(bytes.len() as u64),
+            _ => std::time::Duration::ZERO,
+        };
+        async move {
+            tokio::time::sleep(delay).await;
+            chunk
+        }
+    });
+    Body::from_stream(stream)
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+// Only a single "bytes=" range is supported; anything else is ignored and the whole file is served
+fn parse_range(value: Option<&HeaderValue>, len: u64) -> ByteRange {
+    let Some(spec) = value.and_then(|v| v.to_str().ok()).and_then(|v| v.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => (len.saturating_sub(suffix), len.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (start, "") => match start.parse::<u64>() {
+            Ok(start) => (start, len.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+            _ => return ByteRange::Full,
+        },
+    };
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end)
+}
+
+pub fn make_etag(metadata: &std::fs::Metadata) -> String {
+    let mtime = metadata.modified().ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", metadata.len(), mtime)
 }
 
-async fn serve_large_file(Path(filename): Path<String>, State(state): State<AppState>) -> Response {
-    let try_find = jbod::find_file(&state.src_paths, &PathBuf::from(&filename));
+impl ChecksumCache {
+    // The entry of this version of the file, a new one if it has changed
+    fn entry(&mut self, path: &std::path::Path, len: u64, mtime: SystemTime) -> Arc<CachedChecksum> {
+        self.uses += 1;
+        let cached = self.entries.remove(path)
+            .map(|(last_use, cached)| {
+                self.lru.remove(&last_use);
+                cached
+            })
+            .filter(|cached| (cached.len, cached.mtime) == (len, mtime))
+            .unwrap_or_else(|| Arc::new(CachedChecksum { len, mtime, checksum: tokio::sync::OnceCell::new() }));
+        self.entries.insert(path.to_path_buf(), (self.uses, cached.clone()));
+        self.lru.insert(self.uses, path.to_path_buf());
+        while self.entries.len() > self.capacity {
+            let (_last_use, path) = self.lru.pop_first().unwrap();
+            self.entries.remove(&path);
+        }
+        cached
+    }
+}
+
+// Reading the whole file takes up a stream of the client, like a download does. Fails with 429 when there's none left
+async fn file_checksum(cache: &Mutex<ChecksumCache>, limits: &Arc<StreamLimits>, ip: IpAddr, path: &std::path::Path, metadata: &std::fs::Metadata) -> Result<String, StatusCode> {
+    let mtime = metadata.modified().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let cached = cache.lock().unwrap().entry(path, metadata.len(), mtime);
+
+    // A failed computation isn't kept, the next request tries again
+    let checksum = cached.checksum.get_or_try_init(|| async {
+        let Some(_guard) = limits.try_acquire(ip) else {
+            debug!("Too many streams, rejecting checksum: {} {}", ip, path.display());
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        };
+        let hashed = tokio::task::spawn_blocking({
+            let path = path.to_path_buf();
+            move || checksum::hash_file(&path)
+        }).await;
+        match hashed {
+            Ok(Ok(checksum)) => Ok(checksum),
+            Ok(Err(err)) => {
+                error!("Checksum computation failed: {} {}", path.display(), err);
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }).await;
+    checksum.cloned()
+}
+
+// The client is asked to come back once one of its streams is done
+fn too_many_requests() -> Response {
+    let mut response = StatusCode::TOO_MANY_REQUESTS.into_response();
+    response.headers_mut().insert(header::RETRY_AFTER, RETRY_AFTER_SECS.into());
+    response
+}
+
+// Hashes a whole file as it's streamed, the checksum is kept as soon as the last byte has been read, before it's sent
+struct HashingReader<R> {
+    inner: R,
+    checksum: checksum::Checksum,
+    left: u64,
+    path: PathBuf,
+    cached: Option<Arc<CachedChecksum>>,
+}
+
+impl<R> HashingReader<R> {
+    fn new(inner: R, path: PathBuf, cached: Arc<CachedChecksum>) -> HashingReader<R> {
+        HashingReader { inner, checksum: checksum::Checksum::new(), left: cached.len, path, cached: Some(cached) }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let read = &buf.filled()[filled..];
+        let Some(cached) = &this.cached else {
+            return Poll::Ready(Ok(()));
+        };
+        if read.len() as u64 > this.left || (read.is_empty() && this.left > 0) {
+            // Not the version the checksum is for
+            this.cached = None;
+            return Poll::Ready(Ok(()));
+        }
+        std::io::Write::write_all(&mut this.checksum, read)?;
+        this.left -= read.len() as u64;
+        // Unless the file has changed while it was read
+        let unchanged = || std::fs::metadata(&this.path).is_ok_and(|m| m.len() == cached.len && m.modified().ok() == Some(cached.mtime));
+        if this.left == 0 && unchanged() {
+            let _ = cached.checksum.set(this.checksum.finish());
+            this.cached = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+async fn serve_large_file(Path(filename): Path<String>, State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, req_headers: HeaderMap) -> Response {
+    let try_find = jbod::find_served_file(&state.src_paths, &PathBuf::from(&filename), state.allow_external_symlinks);
     if try_find.is_none() {
         return StatusCode::NOT_FOUND.into_response();
     }
 
     let path = try_find.unwrap();
+    let Ok(mut file) = File::open(&path).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let Ok(metadata) = file.metadata().await.and_then(|m| if m.is_file() { Ok(m) } else { Err(std::io::ErrorKind::InvalidInput.into()) }) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let len = metadata.len();
+    let etag = make_etag(&metadata);
+    // Only a checksum already known is sent, nothing waits for the file to be read
+    let cached = state.checksums.as_ref().zip(metadata.modified().ok()).map(|(cache, mtime)| cache.lock().unwrap().entry(&path, len, mtime));
+    let checksum = cached.as_ref().and_then(|cached| cached.checksum.get().cloned());
+
+    let Some(guard) = state.limits.try_acquire(addr.ip()) else {
+        debug!("Too many streams, rejecting: {} {}", addr.ip(), path.display());
+        return too_many_requests();
+    };
     info!("Got request: {}", path.display());
 
-    match File::open(&path).await {
-        Ok(file) => {
-            let stream = ReaderStream::new(file);
-            let body = axum::body::Body::from_stream(stream);
+    // Ranges refer to the original file, the encoding only applies to the bytes on the wire
+    let encoding = compression::negotiate(req_headers.get(header::ACCEPT_ENCODING)).filter(|_| compression::is_compressible(&path));
+    let Ok(encoder) = encoding.map(Encoder::new).transpose() else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
 
-            let metadata = tokio::fs::metadata(&path).await.ok();
-            let len = metadata.map(|m| m.len());
+    // If-Range: serve the requested range only if the file is still the same one the client has seen
+    let if_range_matches = req_headers.get(header::IF_RANGE).is_none_or(|v| v.as_bytes() == etag.as_bytes());
+    let range = if if_range_matches { parse_range(req_headers.get(header::RANGE), len) } else { ByteRange::Full };
 
-            let mut response = Response::new(body);
-            let headers = response.headers_mut();
-            headers.insert(header::CONTENT_TYPE, HeaderValue::from_str("application/octet-stream").unwrap());
+    let (status, body, content_length) = match range {
+        // The checksum is computed on the way, so it's known by the time the client asks for it
+        ByteRange::Full => match cached.as_ref().filter(|_| checksum.is_none()) {
+            Some(cached) => (StatusCode::OK, limited_body(HashingReader::new(file, path.clone(), cached.clone()), guard, encoder), len),
+            None => (StatusCode::OK, limited_body(file, guard, encoder), len),
+        },
+        ByteRange::Partial(start, end) => {
+            if file.seek(SeekFrom::Start(start)).await.is_err() {
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+            (StatusCode::PARTIAL_CONTENT, limited_body(file.take(end - start + 1), guard, encoder), end - start + 1)
+        }
+        ByteRange::Unsatisfiable => {
+            let mut response = StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+            response.headers_mut().insert(header::CONTENT_RANGE, format!("bytes */{len}").parse().unwrap());
+            return response;
+        }
+    };
+
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_str("application/octet-stream").unwrap());
+    match encoding {
+        Some(encoding) => {
+            headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+            headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+        }
+        None => {
+            headers.insert(header::CONTENT_LENGTH, content_length.to_string().parse().unwrap());
+        }
+    }
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(header::ETAG, etag.parse().unwrap());
+    match checksum {
+        Some(checksum) => {
+            headers.insert(checksum::CHECKSUM_HEADER, checksum.parse().unwrap());
+        }
+        None if cached.is_some() => {
+            headers.insert(checksum::CHECKSUM_PENDING_HEADER, HeaderValue::from_static("1"));
+        }
+        None => {},
+    }
+    if let ByteRange::Partial(start, end) = range {
+        headers.insert(header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}").parse().unwrap());
+    }
+
+    response
+}
+
+// Checksum of a file, for the clients verifying their copies
+async fn get_checksum(Path(filename): Path<String>, State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>) -> Response {
+    let Some(path) = jbod::find_served_file(&state.src_paths, &PathBuf::from(&filename), state.allow_external_symlinks) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let Ok(metadata) = tokio::fs::metadata(&path).await.and_then(|m| if m.is_file() { Ok(m) } else { Err(std::io::ErrorKind::InvalidInput.into()) }) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    // Without --checksum, the result isn't kept
+    let cache = state.checksums.clone().unwrap_or_default();
+    match file_checksum(&cache, &state.limits, addr.ip(), &path, &metadata).await {
+        Ok(checksum) => checksum.into_response(),
+        Err(StatusCode::TOO_MANY_REQUESTS) => too_many_requests(),
+        Err(status) => status.into_response(),
+    }
+}
+
+// Data extents of a sparse file, so that the client can skip downloading its holes
+async fn get_extents(Path(filename): Path<String>, State(state): State<AppState>) -> Response {
+    let Some(path) = jbod::find_served_file(&state.src_paths, &PathBuf::from(&filename), state.allow_external_symlinks) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let extents = tokio::task::spawn_blocking(move || std::fs::File::open(path).and_then(|file| disk_space::data_extents(&file))).await;
+    match extents {
+        Ok(Ok(extents)) => Json(extents).into_response(),
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
 
-            if let Some(len) = len {
-                headers.insert(header::CONTENT_LENGTH, len.to_string().parse().unwrap());
+// GET /list parameters: prefix (the subtree to be walked), include and exclude (rsync-like patterns, in order), min_size and max_size
+fn parse_list_query(params: &[(String, String)]) -> Result<(PathBuf, Filter), String> {
+    let mut prefix = PathBuf::new();
+    let mut filter = Filter::default();
+    for (key, value) in params {
+        match key.as_str() {
+            "prefix" => {
+                prefix = PathBuf::from(value);
+                if !prefix.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+                    return Err(format!("invalid prefix: {value}"));
+                }
             }
+            "include" => filter.rules.push(FilterRule::include(value)?),
+            "exclude" => filter.rules.push(FilterRule::exclude(value)?),
+            "min_size" => filter.min_size = Some(parse_size(value)?),
+            "max_size" => filter.max_size = Some(parse_size(value)?),
+            _ => return Err(format!("unknown parameter: {key}")),
+        }
+    }
+    Ok((prefix, filter))
+}
+
+async fn get_file_list(State(state): State<AppState>, Query(params): Query<Vec<(String, String)>>, req_headers: HeaderMap) -> Response {
+    let (prefix, filter) = match parse_list_query(&params) {
+        Ok(query) => query,
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    // Older clients expect a single JSON document
+    let ndjson = req_headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()).is_some_and(|v| v.contains(NDJSON));
+    if ndjson {
+        return stream_file_list(state, prefix, filter, compression::negotiate(req_headers.get(header::ACCEPT_ENCODING)));
+    }
+
+    let mut list = vec![];
+    let walked = walk_list(&state, &prefix, |item| {
+        list.push(item);
+        Ok(())
+    });
+    // A single JSON array has no room for the warnings, they are only logged
+    if let Err(err) = walked {
+        error!("Listing failed: {}", err);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    let listed = list.len();
+    if !filter.is_empty() {
+        list.retain(|item| filter.matches(item));
+    }
+    let filtered = listed - list.len();
 
+    let mut response = match compression::negotiate(req_headers.get(header::ACCEPT_ENCODING)) {
+        None => Json(list).into_response(),
+        Some(encoding) => {
+            let Ok(body) = serde_json::to_vec(&list).map_err(std::io::Error::from).and_then(|json| compression::compress(&json, encoding)) else {
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            };
+            let mut response = Response::new(Body::from(body));
+            let headers = response.headers_mut();
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+            headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
             response
         }
-        Err(_) => {
-            StatusCode::NOT_FOUND.into_response()
+    };
+    response.headers_mut().insert(FILTERED_HEADER, filtered.into());
+    response
+}
+
+// From the index if there is one, otherwise straight from the disks. Returns what couldn't be listed, which is logged already
+fn walk_list(state: &AppState, prefix: &std::path::Path, visit: impl FnMut(FileEntry) -> std::io::Result<()>) -> std::io::Result<Vec<WalkError>> {
+    match state.index.as_ref().and_then(|index| index.snapshot()) {
+        Some(snapshot) => snapshot.walk(prefix, visit),
+        None => jbod::walk_files(&state.src_paths, prefix, &state.walk, visit),
+    }
+}
+
+async fn get_index_status(State(state): State<AppState>) -> Response {
+    match &state.index {
+        Some(index) => Json(index.status()).into_response(),
+        None => (StatusCode::NOT_FOUND, "The server doesn't keep an index, see serve --index").into_response(),
+    }
+}
+
+// Reads every directory again, the response is sent once the index is up to date
+async fn rescan_index(State(state): State<AppState>) -> Response {
+    let Some(index) = state.index.clone() else {
+        return (StatusCode::NOT_FOUND, "The server doesn't keep an index, see serve --index").into_response();
+    };
+    match tokio::task::spawn_blocking(move || index.rescan(true).map(|()| index.status())).await {
+        Ok(Ok(status)) => Json(status).into_response(),
+        Ok(Err(err)) => {
+            error!("Index rescan failed: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
     }
 }
 
-async fn get_file_list(State(state): State<AppState>) -> Response {
-    Json(jbod::list_files(&state.src_paths)).into_response()
+// Streams the list as NDJSON while the mount points are being walked, so that the downloads can start right away
+fn stream_file_list(state: AppState, prefix: PathBuf, filter: Filter, encoding: Option<Encoding>) -> Response {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(LIST_CHANNEL_CHUNKS);
+    tokio::task::spawn_blocking(move || {
+        let send = |chunk: &mut Vec<u8>| tx.blocking_send(std::mem::take(chunk)).map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "the client has gone away"));
+        let mut chunk = vec![];
+        let mut filtered = 0;
+        let result = walk_list(&state, &prefix, |item| {
+            if !filter.matches(&item) {
+                filtered += 1;
+                return Ok(());
+            }
+            serde_json::to_writer(&mut chunk, &ListLine::Entry(item))?;
+            chunk.push(b'\n');
+            if chunk.len() >= LIST_CHUNK_SIZE {
+                send(&mut chunk)?;
+            }
+            Ok(())
+        });
+        // The summary line is left out on failure, so that the client knows the list is incomplete
+        let result = result.and_then(|warnings| {
+            serde_json::to_writer(&mut chunk, &ListLine::End { end: ListSummary { filtered, warnings } })?;
+            chunk.push(b'\n');
+            send(&mut chunk)
+        });
+        if let Err(err) = result {
+            error!("Listing failed: {}", err);
+        }
+    });
+
+    let stream = futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx).map(|chunk| chunk.map(|chunk| Ok(Bytes::from(chunk)))));
+    let body = match encoding.map(Encoder::new).transpose() {
+        Ok(Some(encoder)) => Body::from_stream(compression::encode_stream(stream, encoder)),
+        Ok(None) => Body::from_stream(stream),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    let mut response = Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(NDJSON));
+    if let Some(encoding) = encoding {
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+        headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    response
 }
 
 async fn check_auth(State(state): State<AppState>, req: Request, next: Next) -> Response {
@@ -66,21 +543,25 @@ async fn check_auth(State(state): State<AppState>, req: Request, next: Next) ->
     }
 }
 
-async fn async_serve(state: AppState, port: u16) {
+async fn async_serve(state: AppState, port: u16) -> Result<()> {
     let app = Router::new()
         .route("/download/{*filename}", get(serve_large_file))
+        .route("/extents/{*filename}", get(get_extents))
+        .route("/checksum/{*filename}", get(get_checksum))
         .route("/list", get(get_file_list))
+        .route("/index", get(get_index_status))
+        .route("/index/rescan", post(rescan_index))
         .layer(from_fn_with_state(state.clone(), check_auth))
         .with_state(state.clone());
     let listener = tokio::net::TcpListener::bind(&format!("0.0.0.0:{}", port))
         .await
-        .unwrap();
-    info!("Listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
-
+        .with_context(|| format!("Couldn't listen on port {}", port))?;
+    info!("Listening on {}", listener.local_addr()?);
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
+    Ok(())
 }
 
-pub fn serve(src_paths: Vec<String>, port: u16) {
+pub fn serve(args: ServeConfig) -> Result<()> {
     let token: String = rand::rng()
         .sample_iter(&Alphanumeric)
         .take(32)
@@ -89,13 +570,162 @@ pub fn serve(src_paths: Vec<String>, port: u16) {
 
     info!("Bearer token for this session: {}", token);
 
+    let walk = WalkOptions { threads: args.walk_threads, one_file_system: args.one_file_system };
+    let index = args.index.then(|| FileIndex::start(args.src_paths.clone(), walk, Duration::from_secs(args.index_refresh), (args.index_full_rescan > 0).then(|| Duration::from_secs(args.index_full_rescan))));
     let state = AppState {
         token: format!("Bearer {token}"),
-        src_paths
+        src_paths: args.src_paths,
+        checksums: args.checksum.then(Arc::default),
+        index,
+        walk,
+        allow_external_symlinks: args.allow_external_symlinks,
+        limits: Arc::new(StreamLimits {
+            per_client: args.max_streams_per_client,
+            total: args.max_streams,
+            bwlimit: args.bwlimit.map(|schedule| Arc::new(RateLimiter::new(schedule))),
+            active: Mutex::new(HashMap::new()),
+        }),
     };
 
     let rt = tokio::runtime::Builder::new_multi_thread().enable_io().enable_time()
-        .build()
-        .unwrap();
-    rt.block_on(async move { async_serve(state, port).await });
+        .build()?;
+    rt.block_on(async move { async_serve(state, args.port).await })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(value: &str, len: u64) -> ByteRange {
+        parse_range(Some(&HeaderValue::from_str(value).unwrap()), len)
+    }
+
+    #[test]
+    fn test_stream_limits() {
+        let limits = Arc::new(StreamLimits { per_client: Some(2), total: Some(3), bwlimit: None, active: Mutex::new(HashMap::new()) });
+        let (client1, client2, client3): (IpAddr, IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap(), "10.0.0.3".parse().unwrap());
+
+        let first = limits.try_acquire(client1).unwrap();
+        let second = limits.try_acquire(client1).unwrap();
+        // Per client
+        assert!(limits.try_acquire(client1).is_none());
+        let third = limits.try_acquire(client2).unwrap();
+        // In total
+        assert!(limits.try_acquire(client3).is_none());
+
+        // The slots are given back as the guards are dropped
+        drop(first);
+        assert!(limits.try_acquire(client1).is_some());
+        drop((second, third));
+        assert!(limits.active.lock().unwrap().is_empty());
+        let _streams: Vec<_> = [client1, client1, client3].into_iter().map(|ip| limits.try_acquire(ip).unwrap()).collect();
+
+        let unlimited = Arc::new(StreamLimits { per_client: None, total: None, bwlimit: None, active: Mutex::new(HashMap::new()) });
+        let _streams: Vec<_> = (0..100).map(|_| unlimited.try_acquire(client1).unwrap()).collect();
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range(None, 100), ByteRange::Full);
+        assert_eq!(range("bytes=10-", 100), ByteRange::Partial(10, 99));
+        assert_eq!(range("bytes=10-19", 100), ByteRange::Partial(10, 19));
+        assert_eq!(range("bytes=90-200", 100), ByteRange::Partial(90, 99));
+        assert_eq!(range("bytes=-30", 100), ByteRange::Partial(70, 99));
+        assert_eq!(range("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=-0", 100), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=20-10", 100), ByteRange::Full);
+        assert_eq!(range("bytes=0-1,5-6", 100), ByteRange::Full);
+        assert_eq!(range("items=0-1", 100), ByteRange::Full);
+    }
+
+    #[test]
+    fn test_file_checksum() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("file.bin");
+        std::fs::write(&path, b"content").unwrap();
+        let cache = Mutex::new(ChecksumCache::default());
+        let limits = Arc::new(StreamLimits { per_client: Some(1), total: None, bwlimit: None, active: Mutex::new(HashMap::new()) });
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let rt = tokio::runtime::Builder::new_multi_thread().build().unwrap();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        let expected = Ok(checksum::hash_file(&path).unwrap());
+        let checksums = rt.block_on(async { tokio::join!(file_checksum(&cache, &limits, ip, &path, &metadata), file_checksum(&cache, &limits, ip, &path, &metadata)) });
+        assert_eq!(checksums, (expected.clone(), expected));
+        // Computed once for this version of the file
+        let cached = cache.lock().unwrap().entries[&path].1.clone();
+        rt.block_on(file_checksum(&cache, &limits, ip, &path, &metadata)).unwrap();
+        assert!(Arc::ptr_eq(&cached, &cache.lock().unwrap().entries[&path].1));
+
+        // Reading the new version takes up a stream
+        std::fs::write(&path, b"new content").unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        let stream = limits.try_acquire(ip).unwrap();
+        assert_eq!(rt.block_on(file_checksum(&cache, &limits, ip, &path, &metadata)), Err(StatusCode::TOO_MANY_REQUESTS));
+        drop(stream);
+        assert_eq!(rt.block_on(file_checksum(&cache, &limits, ip, &path, &metadata)), Ok(checksum::hash_file(&path).unwrap()));
+        assert!(!Arc::ptr_eq(&cached, &cache.lock().unwrap().entries[&path].1));
+    }
+
+    #[test]
+    fn test_checksum_cache_lru() {
+        let mut cache = ChecksumCache { capacity: 2, ..Default::default() };
+        let mtime = SystemTime::UNIX_EPOCH;
+        let (a, b, c) = (std::path::Path::new("a"), std::path::Path::new("b"), std::path::Path::new("c"));
+        let cached_a = cache.entry(a, 1, mtime);
+        cache.entry(b, 1, mtime);
+        // Used again, so b is the one dropped
+        assert!(Arc::ptr_eq(&cached_a, &cache.entry(a, 1, mtime)));
+        cache.entry(c, 1, mtime);
+        assert_eq!((cache.entries.len(), cache.lru.len()), (2, 2));
+        assert!(cache.entries.contains_key(a) && cache.entries.contains_key(c));
+        // Another version of the file
+        assert!(!Arc::ptr_eq(&cached_a, &cache.entry(a, 2, mtime)));
+    }
+
+    #[test]
+    fn test_hashing_reader() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("file.bin");
+        std::fs::write(&path, vec![7u8; 100_000]).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let read = |cached: &Arc<CachedChecksum>| rt.block_on(async {
+            let mut reader = HashingReader::new(File::open(&path).await.unwrap(), path.clone(), cached.clone());
+            let mut contents = vec![];
+            reader.read_to_end(&mut contents).await.unwrap();
+            contents.len()
+        });
+
+        let cached = Arc::new(CachedChecksum { len: metadata.len(), mtime: metadata.modified().unwrap(), checksum: tokio::sync::OnceCell::new() });
+        assert_eq!(read(&cached), 100_000);
+        assert_eq!(cached.checksum.get(), Some(&checksum::hash_file(&path).unwrap()));
+
+        // Not kept for a file which isn't the expected version anymore
+        let stale = Arc::new(CachedChecksum { len: 50_000, mtime: metadata.modified().unwrap(), checksum: tokio::sync::OnceCell::new() });
+        assert_eq!(read(&stale), 100_000);
+        assert!(stale.checksum.get().is_none());
+    }
+
+    fn list_query(query: &str) -> Result<(PathBuf, Filter), String> {
+        parse_list_query(&serde_urlencoded::from_str::<Vec<(String, String)>>(query).unwrap())
+    }
+
+    #[test]
+    fn test_parse_list_query() {
+        let (prefix, filter) = list_query("").unwrap();
+        assert_eq!(prefix, PathBuf::new());
+        assert!(filter.is_empty());
+
+        let (prefix, filter) = list_query("prefix=00042%2F&include=*.log&exclude=*&min_size=1K&max_size=2M").unwrap();
+        assert_eq!(prefix, PathBuf::from("00042/"));
+        assert_eq!(filter.rules.iter().map(FilterRule::query_param).collect::<Vec<_>>(), [("include", "*.log"), ("exclude", "*")]);
+        assert_eq!((filter.min_size, filter.max_size), (Some(1024), Some(2 << 20)));
+
+        assert!(list_query("prefix=../etc").is_err());
+        assert!(list_query("prefix=%2Fetc").is_err());
+        assert!(list_query("prefix=a/../../etc").is_err());
+        assert!(list_query("min_size=lots").is_err());
+        assert!(list_query("pattern=*").is_err());
+    }
 }
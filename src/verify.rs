@@ -0,0 +1,217 @@
+use anyhow::{ Result, bail };
+use serde::Serialize;
+use std::collections::{ BTreeMap, HashSet };
+use std::path::{ Path, PathBuf };
+use std::sync::Mutex;
+use log::*;
+use crate::cli::VerifyConfig;
+use crate::client::{ check_dst_paths, fetch_list, fetch_checksum };
+use crate::filelist::{ walk_bfs, FileEntry, EntryKind, WalkOptions };
+use crate::filter::Filter;
+use crate::checksum::hash_file;
+use crate::errors::Failure;
+use crate::report::write_json;
+use crate::jbod;
+
+/// What verify has found, also written by --report
+#[derive(Serialize, Debug, Default)]
+pub struct Differences {
+    // on the server, but on none of the destination mounts
+    pub missing: Vec<PathBuf>,
+    pub size_mismatch: Vec<SizeMismatch>,
+    // on a destination mount, but not on the server
+    pub extra: Vec<PathBuf>,
+    // relpaths found on several destination mounts
+    pub duplicates: Vec<Duplicate>,
+    pub checksum_mismatch: Vec<PathBuf>,
+    // on a destination mount, what couldn't be listed hasn't been compared
+    pub unreadable: Vec<PathBuf>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SizeMismatch {
+    pub path: PathBuf,
+    pub expected: u64,
+    pub found: u64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Duplicate {
+    pub relpath: PathBuf,
+    pub paths: Vec<PathBuf>,
+}
+
+impl Differences {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.size_mismatch.is_empty() && self.extra.is_empty() && self.duplicates.is_empty() && self.checksum_mismatch.is_empty() && self.unreadable.is_empty()
+    }
+}
+
+/// Compares the list with the destination mounts, also returns the (relpath, path) of the files to be checksummed
+pub fn compare(list: &[FileEntry], dst_paths: &[String], prefix: &Path) -> Result<(Differences, Vec<(PathBuf, PathBuf)>)> {
+    let mut diff = Differences::default();
+    let mut same_size = vec![];
+    for item in list {
+        // The copy in use is the same one the downloads go to
+        let Some(path) = jbod::find_file(dst_paths, &item.relpath) else {
+            diff.missing.push(item.relpath.clone());
+            continue;
+        };
+        if item.kind.is_file() {
+            let found = std::fs::symlink_metadata(&path)?.len();
+            if found == item.size {
+                same_size.push((item.relpath.clone(), path));
+            } else {
+                diff.size_mismatch.push(SizeMismatch { path, expected: item.size, found });
+            }
+        }
+    }
+
+    let mut found: BTreeMap<PathBuf, Vec<(PathBuf, EntryKind)>> = BTreeMap::new();
+    for mount_point in dst_paths {
+        let mount_point = PathBuf::from(mount_point);
+        let warnings = walk_bfs(&mount_point, prefix, &WalkOptions::default(), |_item| true, |item| {
+            found.entry(item.relpath.clone()).or_default().push((mount_point.join(&item.relpath), item.kind));
+            Ok(())
+        })?;
+        diff.unreadable.extend(warnings.into_iter().map(|warning| {
+            warn!("Couldn't list {}: {}", mount_point.join(&warning.path).display(), warning.error);
+            mount_point.join(&warning.path)
+        }));
+    }
+    let listed: HashSet<&Path> = list.iter().map(|item| item.relpath.as_path()).collect();
+    for (relpath, copies) in found {
+        if !listed.contains(relpath.as_path()) {
+            diff.extra.extend(copies.into_iter().map(|(path, _kind)| path));
+        } else if copies.len() > 1 && copies.iter().all(|(_path, kind)| *kind != EntryKind::Dir) {
+            diff.duplicates.push(Duplicate { relpath, paths: copies.into_iter().map(|(path, _kind)| path).collect() });
+        }
+    }
+    Ok((diff, same_size))
+}
+
+// Returns the files whose checksum differs, and the number of files which couldn't be checked
+fn compare_checksums(args: &VerifyConfig, files: Vec<(PathBuf, PathBuf)>) -> (Vec<PathBuf>, usize) {
+    info!("Comparing the checksums of {} files", files.len());
+    let queue = Mutex::new(files.into_iter());
+    let mismatches = Mutex::new(vec![]);
+    let failures = Mutex::new(0);
+    std::thread::scope(|scope| {
+        for _ in 0..args.threads.max(1) {
+            scope.spawn(|| {
+                let agent = ureq::agent();
+                loop {
+                    let Some((relpath, path)) = queue.lock().unwrap().next() else {
+                        return;
+                    };
+                    debug!("Checksumming: {}", path.display());
+                    match fetch_checksum(&agent, &args.url, &args.auth, &relpath).and_then(|expected| Ok((expected, hash_file(&path)?))) {
+                        Ok((expected, checksum)) if expected == checksum => {},
+                        Ok(_) => mismatches.lock().unwrap().push(path),
+                        Err(err) => {
+                            error!("Checksum comparison failed: {} {:#}", path.display(), err);
+                            *failures.lock().unwrap() += 1;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    let mut mismatches = mismatches.into_inner().unwrap();
+    mismatches.sort();
+    (mismatches, failures.into_inner().unwrap())
+}
+
+pub fn run_verify(args: VerifyConfig) -> Result<()> {
+    check_dst_paths(&args.dst_paths)?;
+    let mut list = vec![];
+    let summary = fetch_list(&args.url, &args.auth, false, args.prefix.as_deref(), &Filter::default(), |item| {
+        list.push(item);
+        Ok(())
+    })?;
+
+    info!("Walking the destination");
+    let prefix = args.prefix.clone().unwrap_or_default();
+    let (mut diff, same_size) = compare(&list, &args.dst_paths, &prefix)?;
+    let mut failures = 0;
+    if args.checksum {
+        (diff.checksum_mismatch, failures) = compare_checksums(&args, same_size);
+    }
+
+    for relpath in &diff.missing {
+        warn!("Missing: {}", relpath.display());
+    }
+    for mismatch in &diff.size_mismatch {
+        warn!("Size mismatch: {} {} bytes expected, {} found", mismatch.path.display(), mismatch.expected, mismatch.found);
+    }
+    for path in &diff.extra {
+        warn!("Not on the server: {}", path.display());
+    }
+    for duplicate in &diff.duplicates {
+        let paths: Vec<String> = duplicate.paths.iter().map(|path| path.display().to_string()).collect();
+        warn!("Found on several disks: {}", paths.join(", "));
+    }
+    for path in &diff.checksum_mismatch {
+        warn!("Checksum mismatch: {}", path.display());
+    }
+    info!("Verification done. Files listed: {} missing: {} size mismatches: {} not on the server: {} duplicates: {} checksum mismatches: {} unreadable: {}",
+        list.len(), diff.missing.len(), diff.size_mismatch.len(), diff.extra.len(), diff.duplicates.len(), diff.checksum_mismatch.len(), diff.unreadable.len());
+
+    if let Some(path) = &args.report {
+        write_json(&diff, path)?;
+    }
+
+    if !diff.is_empty() {
+        return Err(Failure::Mismatch.into());
+    }
+    if failures > 0 {
+        bail!("{} checksums couldn't be compared", failures);
+    }
+    // What is below them hasn't been compared
+    if !summary.warnings.is_empty() {
+        bail!("The server couldn't list {} paths", summary.warnings.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let (mount_point1, mount_point2) = (tempdir.path().join("1"), tempdir.path().join("2"));
+        let dst_paths = vec![mount_point1.to_str().unwrap().to_owned(), mount_point2.to_str().unwrap().to_owned()];
+        std::fs::create_dir_all(mount_point1.join("dir")).unwrap();
+        std::fs::create_dir_all(mount_point2.join("dir")).unwrap();
+        std::fs::write(mount_point1.join("dir/ok.bin"), b"same").unwrap();
+        std::fs::write(mount_point2.join("dir/ok.bin"), b"sam").unwrap();
+        std::fs::write(mount_point1.join("dir/short.bin"), b"short").unwrap();
+        std::fs::write(mount_point2.join("extra.bin"), b"extra").unwrap();
+
+        let file = |relpath: &str, size| FileEntry { relpath: PathBuf::from(relpath), size, ..Default::default() };
+        let list = [file("dir/ok.bin", 4), file("dir/short.bin", 10), file("dir/missing.bin", 1)];
+        let (diff, same_size) = compare(&list, &dst_paths, Path::new("")).unwrap();
+        assert_eq!(diff.missing, vec![PathBuf::from("dir/missing.bin")]);
+        assert_eq!(diff.size_mismatch, vec![SizeMismatch { path: mount_point1.join("dir/short.bin"), expected: 10, found: 5 }]);
+        assert_eq!(diff.extra, vec![mount_point2.join("extra.bin")]);
+        assert_eq!(diff.duplicates, vec![Duplicate { relpath: PathBuf::from("dir/ok.bin"), paths: vec![mount_point1.join("dir/ok.bin"), mount_point2.join("dir/ok.bin")] }]);
+        assert_eq!(same_size, vec![(PathBuf::from("dir/ok.bin"), mount_point1.join("dir/ok.bin"))]);
+
+        let (diff, _same_size) = compare(&list, &dst_paths, Path::new("dir")).unwrap();
+        assert!(diff.extra.is_empty());
+
+        // What can't be listed is a difference, its files haven't been compared
+        std::fs::create_dir_all(mount_point2.join("locked/dir")).unwrap();
+        std::fs::set_permissions(mount_point2.join("locked"), std::fs::Permissions::from_mode(0o000)).unwrap();
+        // root reads it anyway
+        if std::fs::read_dir(mount_point2.join("locked")).is_ok() {
+            return;
+        }
+        let (diff, _same_size) = compare(&list, &dst_paths, Path::new("")).unwrap();
+        std::fs::set_permissions(mount_point2.join("locked"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(diff.unreadable, vec![mount_point2.join("locked")]);
+    }
+}